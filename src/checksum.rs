@@ -0,0 +1,412 @@
+//! Checksums and hashes for verifying stored values while parsing. The
+//! cryptographic hashes need the `checksum` feature.
+
+use crate::{KError, KResult, KStream, ValidationFailedError, ValidationKind};
+
+#[cfg(feature = "checksum")]
+use md5::Md5;
+#[cfg(feature = "checksum")]
+use sha1::Sha1;
+#[cfg(feature = "checksum")]
+use sha2::{Digest, Sha256};
+
+/// CRC-32 as used by zlib, PNG, ZIP and Ethernet.
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = flate2::Crc::new();
+    crc.update(bytes);
+    crc.sum()
+}
+
+/// CRC-16/CCITT-FALSE: polynomial 0x1021, initial value 0xFFFF, not
+/// reflected.
+pub fn crc16_ccitt(bytes: &[u8]) -> u16 {
+    bytes.iter().fold(0xffff, |mut crc, &b| {
+        crc ^= u16::from(b) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+        crc
+    })
+}
+
+/// CRC-16/ARC (also known as CRC-16/IBM): polynomial 0x8005, initial
+/// value 0, reflected.
+pub fn crc16_arc(bytes: &[u8]) -> u16 {
+    bytes.iter().fold(0, |mut crc, &b| {
+        crc ^= u16::from(b);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xa001
+            } else {
+                crc >> 1
+            };
+        }
+        crc
+    })
+}
+
+/// Parameters of a CRC in the Rocksoft / "reveng" model. `poly`, `init`
+/// and `xorout` are given unreflected and use only the low `width` bits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CrcParams {
+    pub width: u8,
+    pub poly: u64,
+    pub init: u64,
+    pub refin: bool,
+    pub refout: bool,
+    pub xorout: u64,
+}
+
+impl CrcParams {
+    /// Look up a preset from `CRC_PRESETS`, ignoring case.
+    pub fn from_name(name: &str) -> Option<CrcParams> {
+        CRC_PRESETS
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|&(_, p)| p)
+    }
+}
+
+const fn crc(width: u8, poly: u64, init: u64, refl: bool, xorout: u64) -> CrcParams {
+    CrcParams {
+        width,
+        poly,
+        init,
+        refin: refl,
+        refout: refl,
+        xorout,
+    }
+}
+
+/// Named CRC variants, using the names of the reveng catalogue plus a few
+/// common aliases.
+pub static CRC_PRESETS: &[(&str, CrcParams)] = &[
+    ("CRC-5/USB", crc(5, 0x05, 0x1f, true, 0x1f)),
+    ("CRC-7/MMC", crc(7, 0x09, 0, false, 0)),
+    ("CRC-8/SMBUS", crc(8, 0x07, 0, false, 0)),
+    ("CRC-8/MAXIM-DOW", crc(8, 0x31, 0, true, 0)),
+    ("CRC-8/AUTOSAR", crc(8, 0x2f, 0xff, false, 0xff)),
+    ("CRC-8/SAE-J1850", crc(8, 0x1d, 0xff, false, 0xff)),
+    ("CRC-16/ARC", crc(16, 0x8005, 0, true, 0)),
+    ("CRC-16/IBM-3740", crc(16, 0x1021, 0xffff, false, 0)),
+    ("CRC-16/CCITT-FALSE", crc(16, 0x1021, 0xffff, false, 0)),
+    ("CRC-16/XMODEM", crc(16, 0x1021, 0, false, 0)),
+    ("CRC-16/KERMIT", crc(16, 0x1021, 0, true, 0)),
+    ("CRC-16/MODBUS", crc(16, 0x8005, 0xffff, true, 0)),
+    ("CRC-16/IBM-SDLC", crc(16, 0x1021, 0xffff, true, 0xffff)),
+    ("CRC-16/X-25", crc(16, 0x1021, 0xffff, true, 0xffff)),
+    (
+        "CRC-32/ISO-HDLC",
+        crc(32, 0x04c11db7, 0xffffffff, true, 0xffffffff),
+    ),
+    ("CRC-32", crc(32, 0x04c11db7, 0xffffffff, true, 0xffffffff)),
+    (
+        "CRC-32/ISCSI",
+        crc(32, 0x1edc6f41, 0xffffffff, true, 0xffffffff),
+    ),
+    ("CRC-32C", crc(32, 0x1edc6f41, 0xffffffff, true, 0xffffffff)),
+    (
+        "CRC-32/BZIP2",
+        crc(32, 0x04c11db7, 0xffffffff, false, 0xffffffff),
+    ),
+    ("CRC-32/MPEG-2", crc(32, 0x04c11db7, 0xffffffff, false, 0)),
+    (
+        "CRC-32/AUTOSAR",
+        crc(32, 0xf4acfb13, 0xffffffff, true, 0xffffffff),
+    ),
+    ("CRC-64/ECMA-182", crc(64, 0x42f0e1eba9ea3693, 0, false, 0)),
+    (
+        "CRC-64/XZ",
+        crc(64, 0x42f0e1eba9ea3693, u64::MAX, true, u64::MAX),
+    ),
+];
+
+/// Table-driven CRC calculator for arbitrary `CrcParams`. Data can be fed
+/// incrementally with `update`.
+#[derive(Debug, Clone)]
+pub struct Crc {
+    params: CrcParams,
+    table: Box<[u64; 256]>,
+    reg: u64,
+}
+
+impl Crc {
+    pub fn new(params: CrcParams) -> KResult<Self> {
+        if params.width == 0 || params.width > 64 {
+            return Err(KError::InvalidArgument {
+                msg: format!("unsupported CRC width {}", params.width),
+            });
+        }
+        let w = u32::from(params.width);
+        // Reflected CRCs keep the register reflected and right-aligned,
+        // others keep it left-aligned in the 64 bits, so that both can be
+        // processed a byte at a time regardless of the width.
+        let mut table = Box::new([0u64; 256]);
+        for (i, t) in (0u64..).zip(table.iter_mut()) {
+            *t = if params.refin {
+                let poly = reflect(params.poly, w);
+                (0..8).fold(i, |r, _| if r & 1 != 0 { (r >> 1) ^ poly } else { r >> 1 })
+            } else {
+                let poly = params.poly << (64 - w);
+                (0..8).fold(i << 56, |r, _| {
+                    if r >> 63 != 0 {
+                        (r << 1) ^ poly
+                    } else {
+                        r << 1
+                    }
+                })
+            };
+        }
+        let mut crc = Crc {
+            params,
+            table,
+            reg: 0,
+        };
+        crc.reset();
+        Ok(crc)
+    }
+
+    pub fn from_name(name: &str) -> KResult<Self> {
+        let params = CrcParams::from_name(name).ok_or_else(|| KError::InvalidArgument {
+            msg: format!("unknown CRC preset {:?}", name),
+        })?;
+        Crc::new(params)
+    }
+
+    pub fn params(&self) -> &CrcParams {
+        &self.params
+    }
+
+    pub fn reset(&mut self) {
+        self.reg = self.initial();
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        self.reg = self.feed(self.reg, bytes);
+    }
+
+    /// CRC of the data fed so far; does not reset the calculator.
+    pub fn finish(&self) -> u64 {
+        self.output(self.reg)
+    }
+
+    /// CRC of `bytes` alone, independent of any data already fed.
+    pub fn checksum(&self, bytes: &[u8]) -> u64 {
+        self.output(self.feed(self.initial(), bytes))
+    }
+
+    /// CRC of `len` bytes of `io` starting at byte `start`. The data is
+    /// read in small chunks and the stream position is left unchanged.
+    pub fn checksum_stream<S: KStream + ?Sized>(
+        &self,
+        io: &S,
+        start: usize,
+        len: usize,
+    ) -> KResult<u64> {
+        let saved = io.get_state().clone();
+        let feed = || {
+            io.seek(start)?;
+            let mut reg = self.initial();
+            let mut buf = [0u8; 4096];
+            let mut left = len;
+            while left > 0 {
+                let n = left.min(buf.len());
+                io.read_bytes_into(&mut buf[..n])?;
+                reg = self.feed(reg, &buf[..n]);
+                left -= n;
+            }
+            Ok(reg)
+        };
+        let res = feed();
+        *io.get_state_mut() = saved;
+        res.map(|reg| self.output(reg))
+    }
+
+    fn initial(&self) -> u64 {
+        let w = u32::from(self.params.width);
+        if self.params.refin {
+            reflect(self.params.init, w)
+        } else {
+            self.params.init << (64 - w)
+        }
+    }
+
+    fn feed(&self, reg: u64, bytes: &[u8]) -> u64 {
+        let t = &self.table;
+        if self.params.refin {
+            bytes
+                .iter()
+                .fold(reg, |r, &b| (r >> 8) ^ t[usize::from((r as u8) ^ b)])
+        } else {
+            bytes.iter().fold(reg, |r, &b| {
+                (r << 8) ^ t[usize::from(((r >> 56) as u8) ^ b)]
+            })
+        }
+    }
+
+    fn output(&self, reg: u64) -> u64 {
+        let p = &self.params;
+        let w = u32::from(p.width);
+        let mut res = if p.refin { reg } else { reg >> (64 - w) };
+        if p.refin != p.refout {
+            res = reflect(res, w);
+        }
+        (res ^ p.xorout) & (u64::MAX >> (64 - w))
+    }
+}
+
+fn reflect(value: u64, width: u32) -> u64 {
+    value.reverse_bits() >> (64 - width)
+}
+
+pub fn adler32(bytes: &[u8]) -> u32 {
+    const MOD: u32 = 65521;
+    // 5552 is the largest chunk length for which `b` cannot overflow
+    let (mut a, mut b) = (1u32, 0u32);
+    for chunk in bytes.chunks(5552) {
+        for &x in chunk {
+            a += u32::from(x);
+            b += a;
+        }
+        a %= MOD;
+        b %= MOD;
+    }
+    (b << 16) | a
+}
+
+#[cfg(feature = "checksum")]
+pub fn md5(bytes: &[u8]) -> Vec<u8> {
+    Md5::digest(bytes).to_vec()
+}
+
+#[cfg(feature = "checksum")]
+pub fn sha1(bytes: &[u8]) -> Vec<u8> {
+    Sha1::digest(bytes).to_vec()
+}
+
+#[cfg(feature = "checksum")]
+pub fn sha256(bytes: &[u8]) -> Vec<u8> {
+    Sha256::digest(bytes).to_vec()
+}
+
+/// Fails with a `ValidationKind::Checksum` error unless the checksum
+/// computed from the data equals the one stored in it.
+pub fn validate_checksum<T: PartialEq>(expected: T, actual: T, src_path: &str) -> KResult<()> {
+    if expected == actual {
+        Ok(())
+    } else {
+        Err(KError::ValidationFailed(ValidationFailedError {
+            kind: ValidationKind::Checksum,
+            src_path: src_path.to_string(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::BytesReader;
+    use flate2::{write::ZlibEncoder, Compression};
+    use std::convert::TryInto;
+
+    #[test]
+    fn checksums() {
+        let data = b"123456789";
+        assert_eq!(crc32(data), 0xcbf43926);
+        assert_eq!(crc16_ccitt(data), 0x29b1);
+        assert_eq!(crc16_arc(data), 0xbb3d);
+        assert_eq!(adler32(data), 0x091e01de);
+        assert_eq!(adler32(&[0xff; 100_000]), {
+            let mut z = ZlibEncoder::new(Vec::new(), Compression::fast());
+            std::io::Write::write_all(&mut z, &[0xff; 100_000]).unwrap();
+            let out = z.finish().unwrap();
+            u32::from_be_bytes(out[out.len() - 4..].try_into().unwrap())
+        });
+
+        assert_eq!(validate_checksum(crc32(data), 0xcbf43926, "/seq/1"), Ok(()));
+        assert_eq!(
+            validate_checksum(adler32(b"abc"), adler32(b"abd"), "/seq/2"),
+            Err(KError::ValidationFailed(ValidationFailedError {
+                kind: ValidationKind::Checksum,
+                src_path: "/seq/2".to_string(),
+            }))
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "checksum")]
+    fn hashes() {
+        assert_eq!(
+            md5(b"")[..],
+            [
+                0xd4, 0x1d, 0x8c, 0xd9, 0x8f, 0x00, 0xb2, 0x04, 0xe9, 0x80, 0x09, 0x98, 0xec, 0xf8,
+                0x42, 0x7e
+            ]
+        );
+        assert_eq!(sha1(b"abc")[..4], [0xa9, 0x99, 0x3e, 0x36]);
+        assert_eq!(sha256(b"abc")[..4], [0xba, 0x78, 0x16, 0xbf]);
+        assert!(validate_checksum(sha1(b"abc"), sha1(b"abd"), "/seq/2").is_err());
+    }
+
+    #[test]
+    fn crc_presets() {
+        let checks: &[(&str, u64)] = &[
+            ("CRC-5/USB", 0x19),
+            ("CRC-7/MMC", 0x75),
+            ("CRC-8/SMBUS", 0xf4),
+            ("CRC-8/MAXIM-DOW", 0xa1),
+            ("CRC-8/AUTOSAR", 0xdf),
+            ("CRC-8/SAE-J1850", 0x4b),
+            ("CRC-16/ARC", 0xbb3d),
+            ("CRC-16/IBM-3740", 0x29b1),
+            ("CRC-16/XMODEM", 0x31c3),
+            ("CRC-16/KERMIT", 0x2189),
+            ("CRC-16/MODBUS", 0x4b37),
+            ("CRC-16/IBM-SDLC", 0x906e),
+            ("CRC-32/ISO-HDLC", 0xcbf43926),
+            ("CRC-32/ISCSI", 0xe3069283),
+            ("CRC-32/BZIP2", 0xfc891918),
+            ("CRC-32/MPEG-2", 0x0376e6e7),
+            ("CRC-32/AUTOSAR", 0x1697d06a),
+            ("CRC-64/ECMA-182", 0x6c40df5f0b497347),
+            ("CRC-64/XZ", 0x995dc9bbdf1939fa),
+        ];
+        for &(name, check) in checks {
+            let crc = Crc::from_name(name).unwrap();
+            assert_eq!(crc.checksum(b"123456789"), check, "{}", name);
+        }
+        assert_eq!(
+            Crc::from_name("CRC-99/NOPE").unwrap_err(),
+            KError::InvalidArgument {
+                msg: "unknown CRC preset \"CRC-99/NOPE\"".to_string()
+            }
+        );
+        let mut params = CrcParams::from_name("CRC-64/XZ").unwrap();
+        params.width = 65;
+        assert!(matches!(
+            Crc::new(params),
+            Err(KError::InvalidArgument { .. })
+        ));
+
+        let mut crc = Crc::from_name("crc-32c").unwrap();
+        crc.update(b"1234");
+        crc.update(b"56789");
+        assert_eq!(crc.finish(), 0xe3069283);
+
+        let data: Vec<u8> = (0..10_000u32).map(|i| (i * 7) as u8).collect();
+        let reader = BytesReader::from(data.clone());
+        reader.read_u2le().unwrap();
+        let crc = Crc::from_name("CRC-16/MODBUS").unwrap();
+        assert_eq!(
+            crc.checksum_stream(&reader, 100, 9000).unwrap(),
+            crc.checksum(&data[100..9100])
+        );
+        assert_eq!(reader.pos(), 2);
+        assert!(crc.checksum_stream(&reader, 9000, 2000).is_err());
+        assert_eq!(reader.pos(), 2);
+    }
+}
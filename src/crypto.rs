@@ -0,0 +1,332 @@
+//! Decryption `process:` transforms, enabled by the `crypto` feature.
+//!
+//! Block cipher modes work on whole blocks only and do not strip any
+//! padding: formats that pad encrypted sections describe the padding
+//! themselves.
+
+use crate::{CustomDecoder, CustomProcessor};
+
+use aes::{Aes128, Aes192, Aes256};
+use chacha20::ChaCha20;
+use cipher::{
+    generic_array::GenericArray, BlockDecrypt, BlockEncrypt, BlockSizeUser, KeyInit, KeyIvInit,
+    StreamCipher,
+};
+use des::{Des, TdesEde2, TdesEde3};
+
+use std::convert::TryInto;
+
+pub fn process_aes_ecb(bytes: &[u8], key: &[u8]) -> Result<Vec<u8>, String> {
+    with_aes(key, |c| ecb(c, bytes, false))
+}
+
+pub fn process_aes_cbc(bytes: &[u8], key: &[u8], iv: &[u8]) -> Result<Vec<u8>, String> {
+    with_aes(key, |c| cbc(c, bytes, iv, false))
+}
+
+/// AES in CTR mode with `iv` as the initial 128-bit big-endian counter.
+pub fn process_aes_ctr(bytes: &[u8], key: &[u8], iv: &[u8]) -> Result<Vec<u8>, String> {
+    with_aes(key, |c| ctr(c, bytes, iv))
+}
+
+pub fn process_des_ecb(bytes: &[u8], key: &[u8]) -> Result<Vec<u8>, String> {
+    ecb(&new_cipher::<Des>(key)?, bytes, false)
+}
+
+pub fn process_des_cbc(bytes: &[u8], key: &[u8], iv: &[u8]) -> Result<Vec<u8>, String> {
+    cbc(&new_cipher::<Des>(key)?, bytes, iv, false)
+}
+
+/// Triple DES (EDE) with a 16-byte (two-key) or 24-byte (three-key) key.
+pub fn process_tdes_ecb(bytes: &[u8], key: &[u8]) -> Result<Vec<u8>, String> {
+    with_tdes(key, |c| ecb(c, bytes, false))
+}
+
+/// Triple DES (EDE) in CBC mode, see `process_tdes_ecb`.
+pub fn process_tdes_cbc(bytes: &[u8], key: &[u8], iv: &[u8]) -> Result<Vec<u8>, String> {
+    with_tdes(key, |c| cbc(c, bytes, iv, false))
+}
+
+pub fn process_rc4(bytes: &[u8], key: &[u8]) -> Result<Vec<u8>, String> {
+    if key.is_empty() || key.len() > 256 {
+        return Err(format!("invalid RC4 key length {}", key.len()));
+    }
+    let mut s: Vec<u8> = (0..=255).collect();
+    let mut j = 0u8;
+    for i in 0..256 {
+        j = j.wrapping_add(s[i]).wrapping_add(key[i % key.len()]);
+        s.swap(i, j.into());
+    }
+    let (mut i, mut j) = (0u8, 0u8);
+    let mut res = bytes.to_vec();
+    for b in &mut res {
+        i = i.wrapping_add(1);
+        j = j.wrapping_add(s[usize::from(i)]);
+        s.swap(i.into(), j.into());
+        *b ^= s[usize::from(s[usize::from(i)].wrapping_add(s[usize::from(j)]))];
+    }
+    Ok(res)
+}
+
+/// ChaCha20 as in RFC 8439: 32-byte key, 12-byte nonce, block counter
+/// starting at 0.
+pub fn process_chacha20(bytes: &[u8], key: &[u8], nonce: &[u8]) -> Result<Vec<u8>, String> {
+    let mut c = ChaCha20::new_from_slices(key, nonce).map_err(|_| {
+        format!(
+            "invalid ChaCha20 key/nonce length {}/{}",
+            key.len(),
+            nonce.len()
+        )
+    })?;
+    let mut res = bytes.to_vec();
+    c.apply_keystream(&mut res);
+    Ok(res)
+}
+
+/// Cipher transforms as a `CustomProcessor`: `decode` decrypts, `encode`
+/// encrypts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProcessCipher {
+    AesEcb { key: Vec<u8> },
+    AesCbc { key: Vec<u8>, iv: Vec<u8> },
+    AesCtr { key: Vec<u8>, iv: Vec<u8> },
+    DesEcb { key: Vec<u8> },
+    DesCbc { key: Vec<u8>, iv: Vec<u8> },
+    TdesEcb { key: Vec<u8> },
+    TdesCbc { key: Vec<u8>, iv: Vec<u8> },
+    Rc4 { key: Vec<u8> },
+    ChaCha20 { key: Vec<u8>, nonce: Vec<u8> },
+}
+
+impl ProcessCipher {
+    fn apply(&self, bytes: &[u8], encrypt: bool) -> Result<Vec<u8>, String> {
+        match self {
+            ProcessCipher::AesEcb { key } => with_aes(key, |c| ecb(c, bytes, encrypt)),
+            ProcessCipher::AesCbc { key, iv } => with_aes(key, |c| cbc(c, bytes, iv, encrypt)),
+            ProcessCipher::AesCtr { key, iv } => process_aes_ctr(bytes, key, iv),
+            ProcessCipher::DesEcb { key } => ecb(&new_cipher::<Des>(key)?, bytes, encrypt),
+            ProcessCipher::DesCbc { key, iv } => cbc(&new_cipher::<Des>(key)?, bytes, iv, encrypt),
+            ProcessCipher::TdesEcb { key } => with_tdes(key, |c| ecb(c, bytes, encrypt)),
+            ProcessCipher::TdesCbc { key, iv } => with_tdes(key, |c| cbc(c, bytes, iv, encrypt)),
+            ProcessCipher::Rc4 { key } => process_rc4(bytes, key),
+            ProcessCipher::ChaCha20 { key, nonce } => process_chacha20(bytes, key, nonce),
+        }
+    }
+}
+
+impl CustomDecoder for ProcessCipher {
+    fn decode(&self, bytes: &[u8]) -> Result<Vec<u8>, String> {
+        self.apply(bytes, false)
+    }
+}
+
+impl CustomProcessor for ProcessCipher {
+    fn encode(&self, bytes: &[u8]) -> Result<Vec<u8>, String> {
+        self.apply(bytes, true)
+    }
+}
+
+/// Object-safe view of a block cipher, so that the modes need not be
+/// generic over the key size specific cipher types.
+trait DynBlockCipher {
+    fn block_size(&self) -> usize;
+    fn encrypt(&self, block: &mut [u8]);
+    fn decrypt(&self, block: &mut [u8]);
+}
+
+impl<C: BlockEncrypt + BlockDecrypt> DynBlockCipher for C {
+    fn block_size(&self) -> usize {
+        <C as BlockSizeUser>::block_size()
+    }
+
+    fn encrypt(&self, block: &mut [u8]) {
+        self.encrypt_block(GenericArray::from_mut_slice(block));
+    }
+
+    fn decrypt(&self, block: &mut [u8]) {
+        self.decrypt_block(GenericArray::from_mut_slice(block));
+    }
+}
+
+fn new_cipher<C: KeyInit>(key: &[u8]) -> Result<C, String> {
+    C::new_from_slice(key).map_err(|_| format!("invalid key length {}", key.len()))
+}
+
+fn with_aes<F>(key: &[u8], f: F) -> Result<Vec<u8>, String>
+where
+    F: FnOnce(&dyn DynBlockCipher) -> Result<Vec<u8>, String>,
+{
+    match key.len() {
+        16 => f(&new_cipher::<Aes128>(key)?),
+        24 => f(&new_cipher::<Aes192>(key)?),
+        32 => f(&new_cipher::<Aes256>(key)?),
+        n => Err(format!("invalid AES key length {}", n)),
+    }
+}
+
+fn with_tdes<F>(key: &[u8], f: F) -> Result<Vec<u8>, String>
+where
+    F: FnOnce(&dyn DynBlockCipher) -> Result<Vec<u8>, String>,
+{
+    match key.len() {
+        16 => f(&new_cipher::<TdesEde2>(key)?),
+        24 => f(&new_cipher::<TdesEde3>(key)?),
+        n => Err(format!("invalid 3DES key length {}", n)),
+    }
+}
+
+fn check_blocks(c: &dyn DynBlockCipher, bytes: &[u8]) -> Result<(), String> {
+    if bytes.len().is_multiple_of(c.block_size()) {
+        Ok(())
+    } else {
+        Err(format!(
+            "length {} is not a multiple of the block size {}",
+            bytes.len(),
+            c.block_size()
+        ))
+    }
+}
+
+fn check_iv(c: &dyn DynBlockCipher, iv: &[u8]) -> Result<(), String> {
+    if iv.len() == c.block_size() {
+        Ok(())
+    } else {
+        Err(format!("invalid IV length {}", iv.len()))
+    }
+}
+
+fn ecb(c: &dyn DynBlockCipher, bytes: &[u8], encrypt: bool) -> Result<Vec<u8>, String> {
+    check_blocks(c, bytes)?;
+    let mut res = bytes.to_vec();
+    for block in res.chunks_exact_mut(c.block_size()) {
+        if encrypt {
+            c.encrypt(block);
+        } else {
+            c.decrypt(block);
+        }
+    }
+    Ok(res)
+}
+
+fn cbc(c: &dyn DynBlockCipher, bytes: &[u8], iv: &[u8], encrypt: bool) -> Result<Vec<u8>, String> {
+    check_blocks(c, bytes)?;
+    check_iv(c, iv)?;
+    let mut res = bytes.to_vec();
+    let mut prev = iv.to_vec();
+    for block in res.chunks_exact_mut(c.block_size()) {
+        if encrypt {
+            block.iter_mut().zip(&prev).for_each(|(b, p)| *b ^= p);
+            c.encrypt(block);
+            prev.copy_from_slice(block);
+        } else {
+            let cipher_block = block.to_vec();
+            c.decrypt(block);
+            block.iter_mut().zip(&prev).for_each(|(b, p)| *b ^= p);
+            prev = cipher_block;
+        }
+    }
+    Ok(res)
+}
+
+fn ctr(c: &dyn DynBlockCipher, bytes: &[u8], iv: &[u8]) -> Result<Vec<u8>, String> {
+    check_iv(c, iv)?;
+    let mut counter = u128::from_be_bytes(
+        iv.try_into()
+            .map_err(|_| "CTR mode needs a 128-bit block cipher".to_string())?,
+    );
+    let mut res = bytes.to_vec();
+    for chunk in res.chunks_mut(16) {
+        let mut keystream = counter.to_be_bytes();
+        c.encrypt(&mut keystream);
+        chunk.iter_mut().zip(&keystream).for_each(|(b, k)| *b ^= k);
+        counter = counter.wrapping_add(1);
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unhex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn cipher_block_modes() {
+        let key = unhex("000102030405060708090a0b0c0d0e0f");
+        let pt = unhex("00112233445566778899aabbccddeeff");
+        let ct = unhex("69c4e0d86a7b0430d8cdb78070b4c55a");
+        assert_eq!(process_aes_ecb(&ct, &key).unwrap(), pt);
+        assert!(process_aes_ecb(&ct[..15], &key).is_err());
+        assert!(process_aes_ecb(&ct, &key[..15]).is_err());
+
+        let iv = unhex("f0f1f2f3f4f5f6f7f8f9fafbfcfdfeff");
+        let cbc = ProcessCipher::AesCbc {
+            key: key.clone(),
+            iv: iv.clone(),
+        };
+        let enc = cbc.encode(&[pt.clone(), pt.clone()].concat()).unwrap();
+        let first: Vec<u8> = pt.iter().zip(&iv).map(|(a, b)| a ^ b).collect();
+        assert_eq!(
+            enc[..16],
+            ProcessCipher::AesEcb { key: key.clone() }
+                .encode(&first)
+                .unwrap()[..]
+        );
+        assert_eq!(
+            process_aes_cbc(&enc, &key, &iv).unwrap(),
+            [&pt[..], &pt[..]].concat()
+        );
+
+        // NIST SP 800-38A, F.5.1
+        let key = unhex("2b7e151628aed2a6abf7158809cf4f3c");
+        let ct = unhex("874d6191b620e3261bef6864990db6ce9806f6");
+        assert_eq!(
+            process_aes_ctr(&ct, &key, &iv).unwrap(),
+            unhex("6bc1bee22e409f96e93d7e117393172aae2d8a")
+        );
+
+        let key = unhex("133457799bbcdff1");
+        let ct = unhex("85e813540f0ab405");
+        assert_eq!(
+            process_des_ecb(&ct, &key).unwrap(),
+            unhex("0123456789abcdef")
+        );
+        assert_eq!(
+            process_tdes_ecb(&ct, &[&key[..], &key[..], &key[..]].concat()).unwrap(),
+            unhex("0123456789abcdef")
+        );
+        let iv = [0u8; 8];
+        assert_eq!(
+            process_des_cbc(&ct, &key, &iv).unwrap(),
+            unhex("0123456789abcdef")
+        );
+        assert_eq!(
+            process_tdes_cbc(&ct, &[&key[..], &key[..]].concat(), &iv).unwrap(),
+            unhex("0123456789abcdef")
+        );
+    }
+
+    #[test]
+    fn cipher_stream() {
+        assert_eq!(
+            process_rc4(&unhex("bbf316e8d940af0ad3"), b"Key").unwrap(),
+            b"Plaintext"
+        );
+        assert!(process_rc4(b"x", b"").is_err());
+
+        // RFC 8439, A.2 test vector #1
+        let ks = process_chacha20(&[0; 16], &[0; 32], &[0; 12]).unwrap();
+        assert_eq!(ks, unhex("76b8e0ada0f13d90405d6ae55386bd28"));
+        let c = ProcessCipher::ChaCha20 {
+            key: vec![7; 32],
+            nonce: vec![1; 12],
+        };
+        assert_eq!(c.decode(&c.encode(b"hello").unwrap()).unwrap(), b"hello");
+        assert!(process_chacha20(b"x", &[0; 31], &[0; 12]).is_err());
+    }
+}
@@ -1,36 +1,286 @@
-use encoding::{label::encoding_from_whatwg_label, DecoderTrap};
-use flate2::read::ZlibDecoder;
+use encoding::{label::encoding_from_whatwg_label, EncodingRef};
+use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression};
 
 use std::{
-    any::{type_name, Any},
+    any::{type_name, Any, TypeId},
+    borrow::Cow,
     cell::{Ref, RefCell, RefMut},
     convert::TryInto,
     fmt,
     io::{Read, Seek, SeekFrom},
-    ops::Deref,
+    marker::PhantomData,
+    ops::{Deref, Index, IndexMut},
     path::Path,
     rc::{Rc, Weak},
+    sync::{Arc, RwLock},
 };
 use unicode_segmentation::UnicodeSegmentation;
 
+mod checksum;
+pub use checksum::*;
+
+#[cfg(feature = "crypto")]
+mod crypto;
+#[cfg(feature = "crypto")]
+pub use crypto::*;
+
 #[derive(Debug, PartialEq, Eq, Clone)]
 #[non_exhaustive]
 pub enum KError {
-    Eof { requested: usize, available: usize },
+    Eof {
+        requested: usize,
+        available: usize,
+    },
     EmptyIterator,
-    UnknownEncoding { name: String },
+    UnknownEncoding {
+        name: String,
+    },
     MissingRoot,
     MissingParent,
-    ReadBitsTooLarge { requested: usize },
+    ReadBitsTooLarge {
+        requested: usize,
+    },
     ValidationFailed(ValidationFailedError),
     NoTerminatorFound,
-    IoError { msg: String },
-    BytesDecodingError { msg: String },
+    IoError {
+        msg: String,
+    },
+    BytesDecodingError {
+        msg: String,
+        offset: usize,
+    },
+    StrEncodingError {
+        msg: String,
+        offset: usize,
+    },
     CastError,
-    UndecidedEndianness { src_path: String },
+    UndecidedEndianness {
+        src_path: String,
+    },
+    /// Step `step` (counting from 0) of a `ProcessPipeline` failed.
+    ProcessFailed {
+        step: usize,
+        name: String,
+        msg: String,
+    },
+    /// An argument that can never work, e.g. an empty XOR key.
+    InvalidArgument { msg: String },
+    /// `source` happened while reading the struct or field named last in
+    /// `path`, which was nested in the ones before it. Only produced by
+    /// `with_context` and inside `with_error_context`.
+    Context {
+        path: Vec<String>,
+        source: Box<KError>,
+    },
 }
 pub type KResult<T> = Result<T, KError>;
 
+impl KError {
+    /// Record that the error happened inside `name`, a type or field
+    /// enclosing any already recorded.
+    pub fn with_context<N: Into<String>>(self, name: N) -> KError {
+        match self {
+            KError::Context { mut path, source } => {
+                path.insert(0, name.into());
+                KError::Context { path, source }
+            }
+            e => KError::Context {
+                path: vec![name.into()],
+                source: Box::new(e),
+            },
+        }
+    }
+
+    /// The error without any `Context` wrapping it.
+    pub fn root_cause(&self) -> &KError {
+        match self {
+            KError::Context { source, .. } => source,
+            e => e,
+        }
+    }
+}
+
+/// Prints the context path, if any, then the underlying error, e.g.
+/// `png::Chunk > png::Ihdr > width: Eof { requested: 4, available: 2 }`.
+impl fmt::Display for KError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KError::Context { path, source } => write!(f, "{}: {:?}", path.join(" > "), source),
+            e => write!(f, "{:?}", e),
+        }
+    }
+}
+
+impl std::error::Error for KError {}
+
+thread_local! {
+    // names of the structs being read, innermost last; `None` unless
+    // inside `with_error_context`
+    static CONTEXT_STACK: RefCell<Option<Vec<&'static str>>> = const { RefCell::new(None) };
+}
+
+/// Runs `f` with error context enabled: errors leaving `read_into` and its
+/// variants, as well as those passed to `recover`, are wrapped in
+/// `KError::Context` naming the structs that were being read. Outside of
+/// it errors are returned exactly as they happened.
+pub fn with_error_context<R, F: FnOnce() -> R>(f: F) -> R {
+    let saved = CONTEXT_STACK.with(|c| c.replace(Some(Vec::new())));
+    let _restore = ContextRestore(saved);
+    f()
+}
+
+/// Puts back the context stack that was active before `with_error_context`.
+struct ContextRestore(Option<Vec<&'static str>>);
+
+impl Drop for ContextRestore {
+    fn drop(&mut self) {
+        CONTEXT_STACK.with(|c| *c.borrow_mut() = self.0.take());
+    }
+}
+
+/// Marks a struct as being read for as long as it lives, if error context
+/// is enabled.
+struct ContextFrame(Option<&'static str>);
+
+impl ContextFrame {
+    fn enter<T>() -> Self {
+        let name = type_name::<T>();
+        ContextFrame(CONTEXT_STACK.with(|c| {
+            c.borrow_mut().as_mut().map(|stack| {
+                stack.push(name);
+                name
+            })
+        }))
+    }
+
+    fn wrap(&self, err: KError) -> KError {
+        match self.0 {
+            Some(name) => err.with_context(name),
+            None => err,
+        }
+    }
+}
+
+impl Drop for ContextFrame {
+    fn drop(&mut self) {
+        if self.0.is_some() {
+            CONTEXT_STACK.with(|c| c.borrow_mut().as_mut().and_then(|stack| stack.pop()));
+        }
+    }
+}
+
+/// `err` wrapped with the structs currently being read, if error context
+/// is enabled.
+fn current_context(err: KError) -> KError {
+    CONTEXT_STACK.with(|c| match c.borrow().as_ref() {
+        Some(stack) => stack
+            .iter()
+            .rev()
+            .fold(err, |e, name| e.with_context(*name)),
+        None => err,
+    })
+}
+
+const HEXDUMP_WIDTH: usize = 16;
+/// Rows shown before and after the ones with the failing bytes.
+const HEXDUMP_CONTEXT: usize = 2;
+
+/// Render `err` for humans with a hexdump of `data` around the failure
+/// and carets under the failing bytes, e.g.
+///
+/// ```text
+/// error: Eof { requested: 4, available: 2 }
+///  --> offset 0x1c, 4 bytes (2 past the end)
+/// 00000000  89 50 4e 47 0d 0a 1a 0a 00 00 00 0d 49 48 44 52  |.PNG........IHDR|
+/// 00000010  00 00 01 00 00 00 01 00 08 06 00 00 1f 15        |..............  |
+///                                               ^^ ^^ ^^ ^^
+/// ```
+///
+/// `pos` is where the failing read started, i.e. the stream position
+/// when the error came up; for `BytesDecodingError` it is the start of
+/// the string, to which the error offset is added. The span is taken
+/// from the error: `requested` bytes for `Eof`, one byte otherwise.
+pub fn render_error(err: &KError, data: &[u8], pos: usize) -> String {
+    let (start, len) = error_span(err, pos);
+    render_hexdump(err, data, 0, data.len(), start, len)
+}
+
+/// `render_error` with an explicit span of `len` bytes at `start`, e.g.
+/// the whole field for a `ValidationFailed` error.
+pub fn render_error_span(err: &KError, data: &[u8], start: usize, len: usize) -> String {
+    render_hexdump(err, data, 0, data.len(), start, len)
+}
+
+fn error_span(err: &KError, pos: usize) -> (usize, usize) {
+    match err.root_cause() {
+        KError::Eof { requested, .. } => (pos, *requested),
+        KError::BytesDecodingError { offset, .. } | KError::StrEncodingError { offset, .. } => {
+            (pos.saturating_add(*offset), 1)
+        }
+        _ => (pos, 1),
+    }
+}
+
+/// `window` holds the bytes of a `size` bytes long stream from `base` on.
+fn render_hexdump(
+    err: &KError,
+    window: &[u8],
+    base: usize,
+    size: usize,
+    start: usize,
+    len: usize,
+) -> String {
+    use std::fmt::Write;
+
+    let end = start.saturating_add(len);
+    let mut out = format!("error: {}\n --> offset {:#x}, {} byte", err, start, len);
+    if len != 1 {
+        out.push('s');
+    }
+    if end > size {
+        let _ = write!(out, " ({} past the end)", end - size.max(start));
+    }
+    out.push('\n');
+
+    let first_row = (start.min(size) / HEXDUMP_WIDTH).saturating_sub(HEXDUMP_CONTEXT);
+    let last_row = end.max(start + 1).min(size + 1).div_ceil(HEXDUMP_WIDTH) + HEXDUMP_CONTEXT;
+    for row in first_row..last_row {
+        let row_start = row * HEXDUMP_WIDTH;
+        if row_start >= size && row_start >= end {
+            break;
+        }
+        let mut hex = String::new();
+        let mut ascii = String::new();
+        let mut carets = String::new();
+        for at in row_start..row_start + HEXDUMP_WIDTH {
+            match at.checked_sub(base).and_then(|i| window.get(i)) {
+                Some(&b) => {
+                    let _ = write!(hex, "{:02x} ", b);
+                    ascii.push(if b.is_ascii_graphic() || b == b' ' {
+                        b as char
+                    } else {
+                        '.'
+                    });
+                }
+                None => {
+                    hex.push_str("   ");
+                    ascii.push(' ');
+                }
+            }
+            carets.push_str(if (start..end).contains(&at) {
+                "^^ "
+            } else {
+                "   "
+            });
+        }
+        let _ = writeln!(out, "{:08x}  {} |{}|", row_start, hex, ascii);
+        if carets.contains('^') {
+            let _ = writeln!(out, "          {}", carets.trim_end());
+        }
+    }
+    out
+}
+
 /// Details of the failed validation.
 ///
 /// <div class="warning">
@@ -53,12 +303,27 @@ pub enum ValidationKind {
     NotAnyOf,
     NotInEnum,
     Expr,
+    Checksum,
 }
 
 pub trait CustomDecoder {
     fn decode(&self, bytes: &[u8]) -> Result<Vec<u8>, String>;
 }
 
+/// A `process:` transform that can also be applied in reverse, for
+/// writing data back. `encode(decode(x))` should give back `x`.
+pub trait CustomProcessor: CustomDecoder {
+    fn encode(&self, bytes: &[u8]) -> Result<Vec<u8>, String>;
+}
+
+/// String decoder for an encoding registered with `register_encoding`.
+pub trait CustomStrDecoder {
+    /// Invalid input should be handled as `policy` asks; with
+    /// `DecodingPolicy::Strict` the error should be
+    /// `KError::BytesDecodingError` pointing at the offending offset.
+    fn decode(&self, bytes: &[u8], policy: DecodingPolicy) -> KResult<String>;
+}
+
 #[derive(Default)]
 pub struct SharedType<T>(RefCell<Weak<T>>);
 
@@ -197,10 +462,33 @@ pub trait KStruct: Default {
         let t = OptRc::from(T::default());
         let root = Self::downcast(_root, t.clone(), true);
         let parent = Self::downcast(_parent, t.clone(), false);
-        T::read(&t, _io, root, parent)?;
+        let frame = ContextFrame::enter::<T>();
+        T::read(&t, _io, root, parent).map_err(|e| frame.wrap(e))?;
         Ok(t)
     }
 
+    /// Lenient counterpart of `read_into`: errors passed to `recover`
+    /// while reading are collected instead of aborting the parse, and a
+    /// fatal error is added to the list too. Either way the (possibly
+    /// partially populated) struct is returned.
+    fn read_into_lenient<S: KStream, T: KStruct + Default + Any>(
+        _io: &S,
+        _root: Option<SharedType<T::Root>>,
+        _parent: Option<SharedType<T::Parent>>,
+    ) -> PartialResult<T> {
+        let t = OptRc::from(T::default());
+        let root = Self::downcast(_root, t.clone(), true);
+        let parent = Self::downcast(_parent, t.clone(), false);
+        let frame = ContextFrame::enter::<T>();
+        let guard = LenientGuard::enter();
+        let res = T::read(&t, _io, root, parent);
+        let mut errors = guard.exit();
+        if let Err(e) = res {
+            errors.push(frame.wrap(e));
+        }
+        PartialResult { value: t, errors }
+    }
+
     /// helper function to special initialize and read struct
     fn read_into_with_init<S: KStream, T: KStruct + Default + Any>(
         _io: &S,
@@ -209,11 +497,12 @@ pub trait KStruct: Default {
         init: &dyn Fn(&mut T) -> KResult<()>,
     ) -> KResult<OptRc<T>> {
         let mut t = OptRc::from(T::default());
-        init(Rc::get_mut(t.get_mut()).unwrap())?;
+        let frame = ContextFrame::enter::<T>();
+        init(Rc::get_mut(t.get_mut()).unwrap()).map_err(|e| frame.wrap(e))?;
 
         let root = Self::downcast(_root, t.clone(), true);
         let parent = Self::downcast(_parent, t.clone(), false);
-        T::read(&t, _io, root, parent)?;
+        T::read(&t, _io, root, parent).map_err(|e| frame.wrap(e))?;
         Ok(t)
     }
 
@@ -247,6 +536,69 @@ pub trait KStruct: Default {
     }
 }
 
+/// Struct returned by `KStruct::read_into_lenient`, with every error met
+/// while reading it, in order.
+#[derive(Debug)]
+pub struct PartialResult<T> {
+    pub value: OptRc<T>,
+    pub errors: Vec<KError>,
+}
+
+impl<T> PartialResult<T> {
+    /// Whether the struct was read without any error.
+    pub fn is_complete(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+thread_local! {
+    // one error list per active `read_into_lenient` call
+    static RECOVERED_ERRORS: RefCell<Vec<Vec<KError>>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Pops the error list of a lenient read even if parsing panics.
+struct LenientGuard;
+
+impl LenientGuard {
+    fn enter() -> Self {
+        RECOVERED_ERRORS.with(|e| e.borrow_mut().push(Vec::new()));
+        LenientGuard
+    }
+
+    fn exit(self) -> Vec<KError> {
+        let errors = RECOVERED_ERRORS.with(|e| e.borrow_mut().pop().unwrap_or_default());
+        std::mem::forget(self);
+        errors
+    }
+}
+
+impl Drop for LenientGuard {
+    fn drop(&mut self) {
+        RECOVERED_ERRORS.with(|e| e.borrow_mut().pop());
+    }
+}
+
+/// Whether the current thread is inside `KStruct::read_into_lenient`.
+pub fn is_lenient() -> bool {
+    RECOVERED_ERRORS.with(|e| !e.borrow().is_empty())
+}
+
+/// Used by generated code around fields it can do without. When reading
+/// leniently, an error is recorded and `Ok(None)` returned so that
+/// parsing can go on; otherwise the error is passed through.
+pub fn recover<T>(res: KResult<T>) -> KResult<Option<T>> {
+    match res {
+        Ok(v) => Ok(Some(v)),
+        Err(e) => RECOVERED_ERRORS.with(|errors| match errors.borrow_mut().last_mut() {
+            Some(errors) => {
+                errors.push(current_context(e));
+                Ok(None)
+            }
+            None => Err(e),
+        }),
+    }
+}
+
 /// Dummy struct used to indicate an absence of value; needed for
 /// root structs to satisfy the associated type bounds in the
 /// `KStruct` trait.
@@ -267,6 +619,202 @@ impl KStruct for KStructUnit {
     }
 }
 
+/// Index of a struct stored in an `Arena`, used instead of `OptRc` and
+/// `SharedType` links by `KArenaStruct`.
+pub struct ArenaRef<T> {
+    index: usize,
+    _type: PhantomData<fn() -> T>,
+}
+
+impl<T> ArenaRef<T> {
+    pub fn index(&self) -> usize {
+        self.index
+    }
+}
+
+impl<T: 'static> ArenaRef<T> {
+    /// The same reference typed as `U`, if `U` is `T`.
+    pub fn cast<U: 'static>(self) -> Option<ArenaRef<U>> {
+        if TypeId::of::<T>() == TypeId::of::<U>() {
+            Some(ArenaRef {
+                index: self.index,
+                _type: PhantomData,
+            })
+        } else {
+            None
+        }
+    }
+}
+
+// manual impls, derive would require the same traits of `T`
+impl<T> Clone for ArenaRef<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for ArenaRef<T> {}
+
+impl<T> PartialEq for ArenaRef<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.index == other.index
+    }
+}
+
+impl<T> Eq for ArenaRef<T> {}
+
+impl<T> std::hash::Hash for ArenaRef<T> {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.index.hash(state);
+    }
+}
+
+impl<T> fmt::Debug for ArenaRef<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ArenaRef({})", self.index)
+    }
+}
+
+/// Typed storage for structs of one type; nodes live as long as the
+/// arena and are addressed by `ArenaRef`.
+#[derive(Debug, Clone)]
+pub struct Arena<T> {
+    items: Vec<T>,
+}
+
+impl<T> Default for Arena<T> {
+    fn default() -> Self {
+        Arena { items: Vec::new() }
+    }
+}
+
+impl<T> Arena<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_capacity(capacity: usize) -> Self {
+        Arena {
+            items: Vec::with_capacity(capacity),
+        }
+    }
+
+    pub fn alloc(&mut self, value: T) -> ArenaRef<T> {
+        self.items.push(value);
+        ArenaRef {
+            index: self.items.len() - 1,
+            _type: PhantomData,
+        }
+    }
+
+    pub fn get(&self, r: ArenaRef<T>) -> Option<&T> {
+        self.items.get(r.index)
+    }
+
+    pub fn get_mut(&mut self, r: ArenaRef<T>) -> Option<&mut T> {
+        self.items.get_mut(r.index)
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    /// All nodes in allocation order, i.e. parents before their children.
+    pub fn iter(&self) -> impl Iterator<Item = (ArenaRef<T>, &T)> {
+        self.items.iter().enumerate().map(|(index, v)| {
+            (
+                ArenaRef {
+                    index,
+                    _type: PhantomData,
+                },
+                v,
+            )
+        })
+    }
+}
+
+impl<T> Index<ArenaRef<T>> for Arena<T> {
+    type Output = T;
+
+    fn index(&self, r: ArenaRef<T>) -> &T {
+        &self.items[r.index]
+    }
+}
+
+impl<T> IndexMut<ArenaRef<T>> for Arena<T> {
+    fn index_mut(&mut self, r: ArenaRef<T>) -> &mut T {
+        &mut self.items[r.index]
+    }
+}
+
+/// Storage holding an `Arena` for each struct type of a spec. A spec with
+/// a single type can use its `Arena` directly.
+pub trait ArenaStorage<T> {
+    fn arena(&self) -> &Arena<T>;
+    fn arena_mut(&mut self) -> &mut Arena<T>;
+}
+
+impl<T> ArenaStorage<T> for Arena<T> {
+    fn arena(&self) -> &Arena<T> {
+        self
+    }
+
+    fn arena_mut(&mut self) -> &mut Arena<T> {
+        self
+    }
+}
+
+/// Arena-backed alternative to `KStruct`: structs are plain values in an
+/// `ArenaStorage` and refer to their root, parent and children by
+/// `ArenaRef`, so reading needs one allocation per arena growth instead
+/// of an `Rc` per node.
+pub trait KArenaStruct: Default + 'static {
+    type Root: 'static;
+    type Parent: 'static;
+    type Storage: ArenaStorage<Self>;
+
+    /// Parse the struct already allocated at `self_ref` (and any
+    /// children) from the supplied stream
+    fn read<S: KStream>(
+        storage: &mut Self::Storage,
+        self_ref: ArenaRef<Self>,
+        _io: &S,
+        _root: ArenaRef<Self::Root>,
+        _parent: Option<ArenaRef<Self::Parent>>,
+    ) -> KResult<()>;
+
+    /// Allocate a default `T` in `storage` and read it. Without `_root`,
+    /// `T` is the root itself. On error the partially read node stays in
+    /// the arena.
+    fn read_into_arena<S: KStream, T: KArenaStruct<Storage = Self::Storage>>(
+        storage: &mut Self::Storage,
+        _io: &S,
+        _root: Option<ArenaRef<T::Root>>,
+        _parent: Option<ArenaRef<T::Parent>>,
+    ) -> KResult<ArenaRef<T>>
+    where
+        Self::Storage: ArenaStorage<T>,
+    {
+        let t = ArenaStorage::<T>::arena_mut(storage).alloc(T::default());
+        let root = _root.unwrap_or_else(|| {
+            t.cast().unwrap_or_else(|| {
+                panic!(
+                    "`{}` is not a '{}' type",
+                    type_name::<T>(),
+                    type_name::<T::Root>()
+                )
+            })
+        });
+        let frame = ContextFrame::enter::<T>();
+        T::read(storage, t, _io, root, _parent).map_err(|e| frame.wrap(e))?;
+        Ok(t)
+    }
+}
+
 impl From<std::io::Error> for KError {
     fn from(err: std::io::Error) -> Self {
         Self::IoError {
@@ -275,6 +823,61 @@ impl From<std::io::Error> for KError {
     }
 }
 
+impl From<KError> for std::io::Error {
+    fn from(err: KError) -> Self {
+        let kind = match err.root_cause() {
+            KError::Eof { .. } => std::io::ErrorKind::UnexpectedEof,
+            _ => std::io::ErrorKind::Other,
+        };
+        match err {
+            // came from `std::io` in the first place, hand the message back as is
+            KError::IoError { msg } => std::io::Error::other(msg),
+            err => std::io::Error::new(kind, err.to_string()),
+        }
+    }
+}
+
+// Eof error if fewer than `requested` bytes are left in `io`.
+fn check_available<S: KStream + ?Sized>(io: &S, requested: usize) -> KResult<()> {
+    let available = io.size().saturating_sub(io.pos());
+    if requested > available {
+        return Err(KError::Eof {
+            requested,
+            available,
+        });
+    }
+    Ok(())
+}
+
+/// Generates the `read_<type>_array` / `read_<type>_into` pairs of
+/// `KStream` from the name of the `from_*_bytes` conversion to use.
+macro_rules! bulk_readers {
+    ($($array:ident, $into:ident, $t:ty, $from:ident;)*) => {$(
+        fn $array(&self, count: usize) -> KResult<Vec<$t>> {
+            self.align_to_byte();
+            check_available(self, count.saturating_mul(std::mem::size_of::<$t>()))?;
+            let mut res = vec![<$t>::default(); count];
+            self.$into(&mut res)?;
+            Ok(res)
+        }
+
+        fn $into(&self, dst: &mut [$t]) -> KResult<()> {
+            const SIZE: usize = std::mem::size_of::<$t>();
+            self.align_to_byte();
+            check_available(self, dst.len().saturating_mul(SIZE))?;
+            let mut buf = [0u8; 4096];
+            for chunk in dst.chunks_mut(buf.len() / SIZE) {
+                let bytes = &mut buf[..chunk.len() * SIZE];
+                self.read_bytes_not_aligned_into(bytes)?;
+                for (d, c) in chunk.iter_mut().zip(bytes.chunks_exact(SIZE)) {
+                    *d = <$t>::$from(c.try_into().unwrap());
+                }
+            }
+            Ok(())
+        }
+    )*};
+}
+
 pub trait KStream {
     fn clone(&self) -> BytesReader;
     fn size(&self) -> usize;
@@ -353,10 +956,33 @@ pub trait KStream {
         Ok(f64::from_le_bytes(self.read_bytes(8)?.try_into().unwrap()))
     }
 
-    fn get_state(&self) -> Ref<ReaderState>;
-    fn get_state_mut(&self) -> RefMut<ReaderState>;
+    // Bulk readers: `_into` converts the data a stack-sized chunk at a time
+    // straight into `dst`, `_array` allocates only the result.
+    bulk_readers! {
+        read_s1_array, read_s1_into, i8, from_be_bytes;
+        read_s2be_array, read_s2be_into, i16, from_be_bytes;
+        read_s4be_array, read_s4be_into, i32, from_be_bytes;
+        read_s8be_array, read_s8be_into, i64, from_be_bytes;
+        read_s2le_array, read_s2le_into, i16, from_le_bytes;
+        read_s4le_array, read_s4le_into, i32, from_le_bytes;
+        read_s8le_array, read_s8le_into, i64, from_le_bytes;
+        read_u1_array, read_u1_into, u8, from_be_bytes;
+        read_u2be_array, read_u2be_into, u16, from_be_bytes;
+        read_u4be_array, read_u4be_into, u32, from_be_bytes;
+        read_u8be_array, read_u8be_into, u64, from_be_bytes;
+        read_u2le_array, read_u2le_into, u16, from_le_bytes;
+        read_u4le_array, read_u4le_into, u32, from_le_bytes;
+        read_u8le_array, read_u8le_into, u64, from_le_bytes;
+        read_f4be_array, read_f4be_into, f32, from_be_bytes;
+        read_f8be_array, read_f8be_into, f64, from_be_bytes;
+        read_f4le_array, read_f4le_into, f32, from_le_bytes;
+        read_f8le_array, read_f8le_into, f64, from_le_bytes;
+    }
+
+    fn get_state(&self) -> Ref<'_, ReaderState>;
+    fn get_state_mut(&self) -> RefMut<'_, ReaderState>;
 
-    fn align_to_byte(&self) -> () {
+    fn align_to_byte(&self) {
         let mut inner = self.get_state_mut();
         inner.bits = 0;
         inner.bits_left = 0;
@@ -442,9 +1068,37 @@ pub trait KStream {
         self.read_bytes_not_aligned(len)
     }
 
-    fn read_bytes_not_aligned(&self, len: usize) -> KResult<Vec<u8>>;
+    fn read_bytes_not_aligned(&self, len: usize) -> KResult<Vec<u8>> {
+        // handle read beyond end of file before allocating
+        check_available(self, len)?;
+        let mut buf = vec![0; len];
+        self.read_bytes_not_aligned_into(&mut buf)?;
+        Ok(buf)
+    }
+
+    /// Fill `buf` completely from the stream, as `read_bytes(buf.len())`
+    /// would, without allocating.
+    fn read_bytes_into(&self, buf: &mut [u8]) -> KResult<()> {
+        self.align_to_byte();
+        self.read_bytes_not_aligned_into(buf)
+    }
+
+    /// Non-allocating counterpart of `read_bytes_not_aligned`, which all
+    /// other reads are built on.
+    fn read_bytes_not_aligned_into(&self, buf: &mut [u8]) -> KResult<()>;
+
+    fn read_bytes_full(&self) -> KResult<Vec<u8>> {
+        self.align_to_byte();
+        self.read_bytes_not_aligned(self.size().saturating_sub(self.pos()))
+    }
 
-    fn read_bytes_full(&self) -> KResult<Vec<u8>>;
+    /// Replace the contents of `buf` with the rest of the stream, reusing
+    /// its capacity. Returns the number of bytes read.
+    fn read_bytes_full_into(&self, buf: &mut Vec<u8>) -> KResult<usize> {
+        buf.clear();
+        buf.extend_from_slice(&self.read_bytes_full()?);
+        Ok(buf.len())
+    }
 
     fn read_bytes_term(
         &self,
@@ -455,9 +1109,10 @@ pub trait KStream {
     ) -> KResult<Vec<u8>> {
         self.align_to_byte();
         let mut buf = vec![];
+        let mut c = [0u8];
         loop {
-            let c = match self.read_u1() {
-                Ok(c) => c,
+            let c = match self.read_bytes_not_aligned_into(&mut c) {
+                Ok(()) => c[0],
                 Err(KError::Eof { .. }) => {
                     if eos_error {
                         return Err(KError::NoTerminatorFound);
@@ -478,49 +1133,218 @@ pub trait KStream {
             buf.push(c);
         }
     }
-}
-
-#[derive(Default, Debug, Clone)]
-pub struct ReaderState {
-    pos: usize,
-    bits: u64,
-    bits_left: i32,
-}
-
-trait ReadSeek: Read + Seek {}
 
-impl<T> ReadSeek for T where T: Read + Seek {}
+    /// Read a string of `len` bytes, stripping trailing `pad` bytes and then
+    /// cutting at the first `term` byte, in a single buffer. Equivalent to
+    /// `bytes_to_str(&bytes_terminate(&bytes_strip_right(&read_bytes(len)?, pad), term, false), encoding)`.
+    fn read_str_fixed(
+        &self,
+        len: usize,
+        encoding: &str,
+        pad: Option<u8>,
+        term: Option<u8>,
+    ) -> KResult<String> {
+        let mut buf = self.read_bytes(len)?;
+        if let Some(pad) = pad {
+            buf.truncate(buf.iter().rposition(|&c| c != pad).map_or(0, |i| i + 1));
+        }
+        if let Some(term) = term {
+            if let Some(i) = buf.iter().position(|&c| c == term) {
+                buf.truncate(i);
+            }
+        }
+        owned_bytes_to_str(buf, encoding)
+    }
 
-impl fmt::Display for dyn ReadSeek {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "ReadSeek")
+    /// `read_bytes_term` followed by `bytes_to_str`.
+    fn read_str_term(
+        &self,
+        encoding: &str,
+        term: u8,
+        include: bool,
+        consume: bool,
+        eos_error: bool,
+    ) -> KResult<String> {
+        owned_bytes_to_str(self.read_bytes_term(term, include, consume, eos_error)?, encoding)
     }
-}
 
-impl fmt::Debug for dyn ReadSeek {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "ReadSeek")
+    /// Read a NUL-terminated string, consuming the terminator.
+    fn read_strz(&self, encoding: &str) -> KResult<String> {
+        self.read_str_term(encoding, 0, false, true, true)
     }
-}
 
-#[derive(Debug, Default, Clone)]
-pub struct BytesReader {
-    state: RefCell<ReaderState>,
-    // share same "instance" of data beetween all clones
-    // reposition before each read call
-    buf: OptRc<RefCell<Box<dyn ReadSeek>>>,
-    file_size: u64,
-}
+    /// Read the rest of the stream as a string.
+    fn read_str_eos(&self, encoding: &str) -> KResult<String> {
+        owned_bytes_to_str(self.read_bytes_full()?, encoding)
+    }
 
-impl From<Vec<u8>> for BytesReader {
-    fn from(bytes: Vec<u8>) -> BytesReader {
-        BytesReader::from_buffer(bytes)
+    /// Read `len` bytes as `read_bytes` would, then restore the full reader
+    /// state (position and any partially consumed bits).
+    fn peek_bytes(&self, len: usize) -> KResult<Vec<u8>> {
+        let saved = self.get_state().clone();
+        let res = self.read_bytes(len);
+        *self.get_state_mut() = saved;
+        res
     }
-}
 
-impl From<&[u8]> for BytesReader {
-    fn from(slice: &[u8]) -> BytesReader {
-        BytesReader::from_buffer(slice.to_vec())
+    fn peek_s1(&self) -> KResult<i8> {
+        Ok(self.peek_bytes(1)?[0] as i8)
+    }
+    fn peek_s2be(&self) -> KResult<i16> {
+        Ok(i16::from_be_bytes(self.peek_bytes(2)?.try_into().unwrap()))
+    }
+    fn peek_s4be(&self) -> KResult<i32> {
+        Ok(i32::from_be_bytes(self.peek_bytes(4)?.try_into().unwrap()))
+    }
+    fn peek_s8be(&self) -> KResult<i64> {
+        Ok(i64::from_be_bytes(self.peek_bytes(8)?.try_into().unwrap()))
+    }
+    fn peek_s2le(&self) -> KResult<i16> {
+        Ok(i16::from_le_bytes(self.peek_bytes(2)?.try_into().unwrap()))
+    }
+    fn peek_s4le(&self) -> KResult<i32> {
+        Ok(i32::from_le_bytes(self.peek_bytes(4)?.try_into().unwrap()))
+    }
+    fn peek_s8le(&self) -> KResult<i64> {
+        Ok(i64::from_le_bytes(self.peek_bytes(8)?.try_into().unwrap()))
+    }
+
+    fn peek_u1(&self) -> KResult<u8> {
+        Ok(self.peek_bytes(1)?[0])
+    }
+    fn peek_u2be(&self) -> KResult<u16> {
+        Ok(u16::from_be_bytes(self.peek_bytes(2)?.try_into().unwrap()))
+    }
+    fn peek_u4be(&self) -> KResult<u32> {
+        Ok(u32::from_be_bytes(self.peek_bytes(4)?.try_into().unwrap()))
+    }
+    fn peek_u8be(&self) -> KResult<u64> {
+        Ok(u64::from_be_bytes(self.peek_bytes(8)?.try_into().unwrap()))
+    }
+    fn peek_u2le(&self) -> KResult<u16> {
+        Ok(u16::from_le_bytes(self.peek_bytes(2)?.try_into().unwrap()))
+    }
+    fn peek_u4le(&self) -> KResult<u32> {
+        Ok(u32::from_le_bytes(self.peek_bytes(4)?.try_into().unwrap()))
+    }
+    fn peek_u8le(&self) -> KResult<u64> {
+        Ok(u64::from_le_bytes(self.peek_bytes(8)?.try_into().unwrap()))
+    }
+
+    fn peek_f4be(&self) -> KResult<f32> {
+        Ok(f32::from_be_bytes(self.peek_bytes(4)?.try_into().unwrap()))
+    }
+    fn peek_f8be(&self) -> KResult<f64> {
+        Ok(f64::from_be_bytes(self.peek_bytes(8)?.try_into().unwrap()))
+    }
+    fn peek_f4le(&self) -> KResult<f32> {
+        Ok(f32::from_le_bytes(self.peek_bytes(4)?.try_into().unwrap()))
+    }
+    fn peek_f8le(&self) -> KResult<f64> {
+        Ok(f64::from_le_bytes(self.peek_bytes(8)?.try_into().unwrap()))
+    }
+
+    /// Read `n` bits as `read_bits_int_be` would, then restore the full
+    /// reader state.
+    fn peek_bits_int_be(&self, n: usize) -> KResult<u64> {
+        let saved = self.get_state().clone();
+        let res = self.read_bits_int_be(n);
+        *self.get_state_mut() = saved;
+        res
+    }
+
+    /// Read `n` bits as `read_bits_int_le` would, then restore the full
+    /// reader state.
+    fn peek_bits_int_le(&self, n: usize) -> KResult<u64> {
+        let saved = self.get_state().clone();
+        let res = self.read_bits_int_le(n);
+        *self.get_state_mut() = saved;
+        res
+    }
+
+    /// `render_error` over the bytes of this stream around `pos`, without
+    /// reading the rest of it. The stream position is left unchanged. Fails
+    /// with the read error if those bytes cannot be read.
+    fn render_error(&self, err: &KError, pos: usize) -> KResult<String> {
+        let (start, len) = error_span(err, pos);
+        let size = self.size();
+        let from =
+            (start.min(size) / HEXDUMP_WIDTH).saturating_sub(HEXDUMP_CONTEXT) * HEXDUMP_WIDTH;
+        let to = size.min(
+            start.saturating_add(len.max(1)).div_ceil(HEXDUMP_WIDTH) * HEXDUMP_WIDTH
+                + HEXDUMP_CONTEXT * HEXDUMP_WIDTH,
+        );
+        let saved = self.get_state().clone();
+        let window = self
+            .seek(from)
+            .and_then(|_| self.read_bytes(to.saturating_sub(from)));
+        *self.get_state_mut() = saved;
+        Ok(render_hexdump(err, &window?, from, size, start, len))
+    }
+}
+
+#[derive(Default, Debug, Clone)]
+pub struct ReaderState {
+    pos: usize,
+    bits: u64,
+    bits_left: i32,
+}
+
+trait ReadSeek: Read + Seek {}
+
+impl<T> ReadSeek for T where T: Read + Seek {}
+
+impl fmt::Display for dyn ReadSeek {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ReadSeek")
+    }
+}
+
+impl fmt::Debug for dyn ReadSeek {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ReadSeek")
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct BytesReader {
+    state: RefCell<ReaderState>,
+    // share same "instance" of data beetween all clones
+    // reposition before each read call
+    buf: OptRc<RefCell<Box<dyn ReadSeek>>>,
+    file_size: u64,
+    fill_buf: FillBuf,
+}
+
+/// Window of bytes starting at `pos`, only used by `BufRead`. It is a
+/// cache, so clones start without it and `Debug` leaves the bytes out.
+#[derive(Default)]
+struct FillBuf {
+    data: Vec<u8>,
+    pos: usize,
+}
+
+impl Clone for FillBuf {
+    fn clone(&self) -> Self {
+        FillBuf::default()
+    }
+}
+
+impl fmt::Debug for FillBuf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "FillBuf({}..{})", self.pos, self.pos + self.data.len())
+    }
+}
+
+impl From<Vec<u8>> for BytesReader {
+    fn from(bytes: Vec<u8>) -> BytesReader {
+        BytesReader::from_buffer(bytes)
+    }
+}
+
+impl From<&[u8]> for BytesReader {
+    fn from(slice: &[u8]) -> BytesReader {
+        BytesReader::from_buffer(slice.to_vec())
     }
 }
 
@@ -533,6 +1357,30 @@ impl BytesReader {
             state: RefCell::new(ReaderState::default()),
             file_size,
             buf: OptRc::from(RefCell::new(r)),
+            ..Default::default()
+        })
+    }
+
+    /// Open several files as one logical stream, in the given order (e.g.
+    /// the `.001`, `.002`, ... volumes of a split image).
+    pub fn open_multi<T: AsRef<Path>>(filenames: impl IntoIterator<Item = T>) -> KResult<Self> {
+        let parts = filenames
+            .into_iter()
+            .map(BytesReader::open)
+            .collect::<KResult<Vec<_>>>()?;
+        BytesReader::from_reader(ChainedReader::new(parts))
+    }
+
+    /// Wrap an arbitrary `Read + Seek` source; its size is taken from
+    /// seeking to the end.
+    pub fn from_reader<R: Read + Seek + 'static>(mut reader: R) -> KResult<Self> {
+        let file_size = reader.seek(SeekFrom::End(0))?;
+        let r: Box<dyn ReadSeek> = Box::new(reader);
+        Ok(BytesReader {
+            state: RefCell::new(ReaderState::default()),
+            file_size,
+            buf: OptRc::from(RefCell::new(r)),
+            ..Default::default()
         })
     }
 
@@ -543,6 +1391,7 @@ impl BytesReader {
             state: RefCell::new(ReaderState::default()),
             file_size,
             buf: OptRc::from(RefCell::new(r)),
+            ..Default::default()
         }
     }
 
@@ -566,11 +1415,11 @@ impl KStream for BytesReader {
         Clone::clone(self)
     }
 
-    fn get_state(&self) -> Ref<ReaderState> {
+    fn get_state(&self) -> Ref<'_, ReaderState> {
         self.state.borrow()
     }
 
-    fn get_state_mut(&self) -> RefMut<ReaderState> {
+    fn get_state_mut(&self) -> RefMut<'_, ReaderState> {
         self.state.borrow_mut()
     }
 
@@ -578,195 +1427,1758 @@ impl KStream for BytesReader {
         self.file_size as usize
     }
 
-    fn read_bytes_not_aligned(&self, len: usize) -> KResult<Vec<u8>> {
+    fn read_bytes_not_aligned_into(&self, buf: &mut [u8]) -> KResult<()> {
         // handle read beyond end of file
-        let num_bytes_available = self.size().saturating_sub(self.pos());
-        if len > num_bytes_available {
-            return Err(KError::Eof {
-                requested: len,
-                available: num_bytes_available,
-            });
-        }
+        let len = buf.len();
+        check_available(self, len)?;
         self.sync_pos()?;
-        // let state = self.state.borrow_mut();
-        // state.buf.resize(len, 0);
-        let mut buf = vec![0; len];
         self
             .buf
             .borrow_mut()
-            .read_exact(&mut buf[..])?;
+            .read_exact(buf)?;
         self.get_state_mut().pos += len;
-        Ok(buf)
+        Ok(())
     }
 
     fn read_bytes_full(&self) -> KResult<Vec<u8>> {
+        let mut buf = Vec::new();
+        self.read_bytes_full_into(&mut buf)?;
+        Ok(buf)
+    }
+
+    fn read_bytes_full_into(&self, buf: &mut Vec<u8>) -> KResult<usize> {
         self.align_to_byte();
         self.sync_pos()?;
-        //let state = self.state.borrow_mut();
-        let mut buf = Vec::new();
+        buf.clear();
         let readed = self
             .buf
             .borrow_mut()
-            .read_to_end(&mut buf)?;
+            .read_to_end(buf)?;
         self.get_state_mut().pos += readed;
-        Ok(buf)
+        Ok(readed)
     }
 }
 
-/// Return a byte array that is sized to exclude all trailing instances of the
-/// padding character.
-pub fn bytes_strip_right(bytes: &Vec<u8>, pad: u8) -> Vec<u8> {
-    if let Some(last_non_pad_index) = bytes.iter().rposition(|&c| c != pad) {
-        bytes[..=last_non_pad_index].to_vec()
-    } else {
-        vec![]
-    }
+// std::io adapters, so a parsed region can be handed to other decoders.
+// They honour `pos()` and `size()` exactly like the KStream methods do.
+fn io_read<S: KStream>(io: &S, buf: &mut [u8]) -> std::io::Result<usize> {
+    let len = buf.len().min(io.size().saturating_sub(io.pos()));
+    io.read_bytes_into(&mut buf[..len])?;
+    Ok(len)
 }
 
-/// Return a byte array that contains all bytes up until the
-/// termination byte. Can optionally include the termination byte as well.
-pub fn bytes_terminate(bytes: &Vec<u8>, term: u8, include_term: bool) -> Vec<u8> {
-    if let Some(term_index) = bytes.iter().position(|&c| c == term) {
-        &bytes[..term_index + if include_term { 1 } else { 0 }]
-    } else {
-        bytes
-    }.to_vec()
+fn io_seek<S: KStream>(io: &S, pos: SeekFrom) -> std::io::Result<u64> {
+    let new_pos = match pos {
+        SeekFrom::Start(n) => Some(n),
+        SeekFrom::End(n) => (io.size() as u64).checked_add_signed(n),
+        SeekFrom::Current(n) => (io.pos() as u64).checked_add_signed(n),
+    };
+    let new_pos = new_pos.ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "invalid seek to a negative or overflowing position",
+        )
+    })?;
+    io.seek(new_pos as usize)?;
+    Ok(new_pos)
 }
 
-pub fn bytes_to_str(bytes: &Vec<u8>, label: &str) -> KResult<String> {
-    if let Some(enc) = encoding_from_whatwg_label(label) {
-        return Ok(enc
-            .decode(bytes.as_slice(), DecoderTrap::Replace)
-            .expect("this should never fail because we use DecoderTrap::Replace"));
-    }
-
-    if label.eq_ignore_ascii_case("cp437") || label.eq_ignore_ascii_case("ibm437") {
-        use std::io::BufReader;
-        let reader = BufReader::new(bytes.as_slice());
-        let mut buffer = reader.bytes();
-        let mut r = cp437::Reader::new(&mut buffer);
-        return Ok(r.consume(bytes.len()));
-    }
+/// `Read` and `Seek` for the given `KStream` readers, via `io_read` and
+/// `io_seek`.
+macro_rules! impl_read_seek {
+    ($($t:ty),*) => {$(
+        impl Read for $t {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                io_read(self, buf)
+            }
+        }
 
-    Err(KError::UnknownEncoding {
-        name: label.to_string(),
-    })
+        impl Seek for $t {
+            fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+                io_seek(self, pos)
+            }
+        }
+    )*};
 }
 
-pub fn process_xor_one(bytes: &Vec<u8>, key: u8) -> Vec<u8> {
-    let mut res = bytes.to_vec();
-    for i in &mut res {
-        *i ^= key;
-    }
-    res
+impl_read_seek!(BytesReader, ChainedReader, ExtentReader, ProcessedReader);
+
+// `KStream::clone` for readers that are not a `BytesReader` themselves: a
+// `BytesReader` over a copy of `io`, at the same position.
+fn clone_into_bytes_reader<R>(io: &R) -> BytesReader
+where
+    R: KStream + Clone + Read + Seek + 'static,
+{
+    let r = BytesReader::from_reader(Clone::clone(io))
+        .expect("seeking to the end of a KStream never fails");
+    *r.get_state_mut() = io.get_state().clone();
+    r
 }
 
-pub fn process_xor_many(bytes: &Vec<u8>, key: &[u8]) -> Vec<u8> {
-    let mut res = bytes.to_vec();
-    let mut ki = 0;
-    for i in &mut res {
-        *i ^= key[ki];
-        ki += 1;
-        if ki >= key.len() {
-            ki = 0;
+impl std::io::BufRead for BytesReader {
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        self.align_to_byte();
+        let pos = self.pos();
+        let cached = pos >= self.fill_buf.pos && pos < self.fill_buf.pos + self.fill_buf.data.len();
+        if !cached {
+            let len = self.size().saturating_sub(pos).min(8 * 1024);
+            let mut data = std::mem::take(&mut self.fill_buf.data);
+            data.resize(len, 0);
+            let res = self.read_bytes_into(&mut data);
+            self.get_state_mut().pos = pos;
+            if res.is_err() {
+                // keep the allocation, but never serve a failed read
+                data.clear();
+            }
+            self.fill_buf = FillBuf { data, pos };
+            res?;
         }
+        Ok(&self.fill_buf.data[pos - self.fill_buf.pos..])
     }
-    res
-}
 
-pub fn process_rotate_left(bytes: &Vec<u8>, amount: u8) -> Vec<u8> {
-    let mut res = bytes.to_vec();
-    for i in &mut res {
-        *i = i.rotate_left(amount.into());
+    fn consume(&mut self, amt: usize) {
+        let mut state = self.get_state_mut();
+        state.pos = (state.pos + amt).min(self.file_size as usize);
     }
-    res
 }
 
-pub fn process_zlib(bytes: &Vec<u8>) -> Result<Vec<u8>, String> {
-    let mut dec = ZlibDecoder::new(bytes.as_slice());
-    let mut dec_bytes = Vec::new();
-    dec.read_to_end(&mut dec_bytes).map_err(|e| e.to_string())?;
-    Ok(dec_bytes)
+/// Several readers presented, one after another, as a single logical
+/// stream. Reads may straddle the boundary between parts.
+#[derive(Debug, Default, Clone)]
+pub struct ChainedReader {
+    state: RefCell<ReaderState>,
+    parts: Vec<BytesReader>,
+    // logical offset at which each part starts
+    starts: Vec<usize>,
+    size: usize,
 }
 
-pub fn reverse_string<S: AsRef<str>>(s: S) -> KResult<String> {
-    Ok(s.as_ref().graphemes(true).rev().collect())
+impl ChainedReader {
+    pub fn new(parts: Vec<BytesReader>) -> Self {
+        let mut starts = Vec::with_capacity(parts.len());
+        let mut size = 0;
+        for part in &parts {
+            starts.push(size);
+            size += part.size();
+        }
+        ChainedReader {
+            state: RefCell::new(ReaderState::default()),
+            parts,
+            starts,
+            size,
+        }
+    }
 }
 
-pub fn modulo(a: i64, b: i64) -> i64 {
-    a.rem_euclid(b)
-}
+impl KStream for ChainedReader {
+    fn clone(&self) -> BytesReader {
+        clone_into_bytes_reader(self)
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::io::Write;
-    use tempfile::tempdir;
+    fn get_state(&self) -> Ref<'_, ReaderState> {
+        self.state.borrow()
+    }
 
-    #[test]
-    fn basic_strip_right() {
-        let b = vec![1, 2, 3, 4, 5, 5, 5, 5];
-        let c = bytes_strip_right(&b, 5);
+    fn get_state_mut(&self) -> RefMut<'_, ReaderState> {
+        self.state.borrow_mut()
+    }
 
-        assert_eq!([1, 2, 3, 4], c[..]);
+    fn size(&self) -> usize {
+        self.size
     }
 
-    #[test]
-    fn basic_read_bytes() {
-        let b = vec![1, 2, 3, 4, 5, 6, 7, 8];
-        let reader = BytesReader::from(b);
+    fn read_bytes_not_aligned_into(&self, buf: &mut [u8]) -> KResult<()> {
+        check_available(self, buf.len())?;
+        let mut pos = self.pos();
+        let mut done = 0;
+        while done < buf.len() {
+            // last part starting at or before `pos`; empty parts are skipped
+            // because the part following them has the same start
+            let i = self.starts.partition_point(|&start| start <= pos) - 1;
+            let part = &self.parts[i];
+            let offset = pos - self.starts[i];
+            let n = (buf.len() - done).min(part.size() - offset);
+            part.seek(offset)?;
+            part.read_bytes_into(&mut buf[done..done + n])?;
+            done += n;
+            pos += n;
+        }
+        self.get_state_mut().pos = pos;
+        Ok(())
+    }
+}
 
-        assert_eq!(reader.read_bytes(4).unwrap()[..], [1, 2, 3, 4]);
-        assert_eq!(reader.read_bytes(3).unwrap()[..], [5, 6, 7]);
-        assert_eq!(
-            reader.read_bytes(4).unwrap_err(),
-            KError::Eof {
-                requested: 4,
-                available: 1
+/// One run of a logical stream stored contiguously in the parent stream.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct Extent {
+    pub logical: usize,
+    pub physical: usize,
+    pub len: usize,
+}
+
+/// A logical stream reassembled from extents of a parent stream, as
+/// described by file system runlists or disk image allocation tables.
+/// Ranges not covered by any extent are holes and read as zeros.
+#[derive(Debug, Default, Clone)]
+pub struct ExtentReader {
+    state: RefCell<ReaderState>,
+    parent: BytesReader,
+    // sorted by logical offset, must not overlap
+    extents: Vec<Extent>,
+    size: usize,
+}
+
+impl ExtentReader {
+    /// `size` is the logical size of the stream, which may end in a hole.
+    /// Extents are sorted here; overlapping extents are an error.
+    pub fn new(parent: BytesReader, mut extents: Vec<Extent>, size: usize) -> KResult<Self> {
+        extents.sort_by_key(|e| e.logical);
+        for pair in extents.windows(2) {
+            if pair[0].logical.saturating_add(pair[0].len) > pair[1].logical {
+                return Err(KError::InvalidArgument {
+                    msg: format!(
+                        "extent at logical offset {} overlaps the one at {}",
+                        pair[1].logical, pair[0].logical
+                    ),
+                });
             }
-        );
-        assert_eq!(reader.read_bytes(1).unwrap()[..], [8]);
+        }
+        Ok(ExtentReader {
+            state: RefCell::new(ReaderState::default()),
+            parent,
+            extents,
+            size,
+        })
     }
+}
 
-    #[test]
-    fn read_bits_single() {
-        let b = vec![0x80];
-        let reader = BytesReader::from(b);
-
-        assert_eq!(reader.read_bits_int_be(1).unwrap(), 1);
+impl KStream for ExtentReader {
+    fn clone(&self) -> BytesReader {
+        clone_into_bytes_reader(self)
     }
 
-    #[test]
-    fn read_bits_multiple() {
-        // 0xA0
-        let b = vec![0b10100000];
-        let reader = BytesReader::from(b);
+    fn get_state(&self) -> Ref<'_, ReaderState> {
+        self.state.borrow()
+    }
 
-        assert_eq!(reader.read_bits_int_be(1).unwrap(), 1);
-        assert_eq!(reader.read_bits_int_be(1).unwrap(), 0);
-        assert_eq!(reader.read_bits_int_be(1).unwrap(), 1);
+    fn get_state_mut(&self) -> RefMut<'_, ReaderState> {
+        self.state.borrow_mut()
     }
 
-    #[test]
-    fn read_bits_large() {
-        let b = vec![0b10100000];
-        let reader = BytesReader::from(b);
+    fn size(&self) -> usize {
+        self.size
+    }
 
-        assert_eq!(reader.read_bits_int_be(3).unwrap(), 5);
+    fn read_bytes_not_aligned_into(&self, buf: &mut [u8]) -> KResult<()> {
+        check_available(self, buf.len())?;
+        let mut pos = self.pos();
+        let mut done = 0;
+        while done < buf.len() {
+            let remaining = buf.len() - done;
+            // number of extents starting at or before `pos`
+            let i = self.extents.partition_point(|e| e.logical <= pos);
+            let n = match i.checked_sub(1).map(|i| &self.extents[i]) {
+                Some(e) if pos < e.logical + e.len => {
+                    let n = remaining.min(e.logical + e.len - pos);
+                    self.parent.seek(e.physical + (pos - e.logical))?;
+                    self.parent.read_bytes_into(&mut buf[done..done + n])?;
+                    n
+                }
+                _ => {
+                    let hole_end = self.extents.get(i).map_or(self.size, |e| e.logical);
+                    let n = remaining.min(hole_end - pos);
+                    buf[done..done + n].fill(0);
+                    n
+                }
+            };
+            done += n;
+            pos += n;
+        }
+        self.get_state_mut().pos = pos;
+        Ok(())
     }
+}
 
-    #[test]
-    fn read_bits_span() {
-        let b = vec![0x01, 0x80];
-        let reader = BytesReader::from(b);
+/// Transform applied on the fly by a `ProcessedReader`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StreamProcess {
+    XorOne(u8),
+    XorMany(Vec<u8>),
+    RotateLeft(u8),
+    Zlib,
+}
 
-        assert_eq!(reader.read_bits_int_be(9).unwrap(), 3);
+/// The result of a `process:` transform over a parent stream, computed
+/// lazily as it is read instead of materialized in memory.
+///
+/// Byte-wise transforms (XOR, rotate) map each position directly onto the
+/// parent. Zlib is decompressed sequentially: reading forward continues
+/// the current decoder, seeking backwards restarts it from the beginning.
+/// To process only a section of a stream, pass a reader over that section
+/// (e.g. a clone of an `ExtentReader`).
+pub struct ProcessedReader {
+    state: RefCell<ReaderState>,
+    parent: BytesReader,
+    process: StreamProcess,
+    size: usize,
+    // current decoder and the decoded offset it is at
+    zlib: RefCell<Option<(ZlibDecoder<BytesReader>, usize)>>,
+}
+
+impl ProcessedReader {
+    /// For zlib this decompresses the whole parent once (without keeping
+    /// the output) to find the size of the stream.
+    pub fn new(parent: BytesReader, process: StreamProcess) -> KResult<Self> {
+        let size = match &process {
+            StreamProcess::Zlib => {
+                let mut dec = ZlibDecoder::new(Self::rewound(&parent)?);
+                std::io::copy(&mut dec, &mut std::io::sink())? as usize
+            }
+            StreamProcess::XorMany(key) if key.is_empty() => {
+                return Err(KError::InvalidArgument {
+                    msg: "empty XOR key".to_string(),
+                });
+            }
+            _ => parent.size(),
+        };
+        Ok(ProcessedReader {
+            state: RefCell::new(ReaderState::default()),
+            parent,
+            process,
+            size,
+            zlib: RefCell::new(None),
+        })
     }
 
-    #[test]
-    fn read_bits_too_large() {
-        let b: Vec<u8> = vec![1, 2, 3, 4, 5, 6, 7, 8, 9];
-        let reader = BytesReader::from(b);
+    fn rewound(parent: &BytesReader) -> KResult<BytesReader> {
+        let r = KStream::clone(parent);
+        r.seek(0)?;
+        Ok(r)
+    }
+
+    // fill `buf` with the processed bytes at offset `pos`
+    fn read_processed(&self, buf: &mut [u8], pos: usize) -> KResult<()> {
+        match &self.process {
+            StreamProcess::XorOne(key) => {
+                self.read_parent(buf, pos)?;
+                for b in buf {
+                    *b ^= key;
+                }
+            }
+            StreamProcess::XorMany(key) => {
+                self.read_parent(buf, pos)?;
+                for (i, b) in buf.iter_mut().enumerate() {
+                    *b ^= key[(pos + i) % key.len()];
+                }
+            }
+            StreamProcess::RotateLeft(amount) => {
+                self.read_parent(buf, pos)?;
+                for b in buf {
+                    *b = b.rotate_left((*amount).into());
+                }
+            }
+            StreamProcess::Zlib => self.read_zlib(buf, pos)?,
+        }
+        Ok(())
+    }
+
+    fn read_parent(&self, buf: &mut [u8], pos: usize) -> KResult<()> {
+        self.parent.seek(pos)?;
+        self.parent.read_bytes_into(buf)
+    }
+
+    fn read_zlib(&self, buf: &mut [u8], pos: usize) -> KResult<()> {
+        // the decoder is only put back after a successful read: one that
+        // failed partway is at an unknown offset, so the next read restarts
+        let (mut dec, dec_pos) = match self.zlib.take() {
+            Some((dec, dec_pos)) if dec_pos <= pos => (dec, dec_pos),
+            _ => (ZlibDecoder::new(Self::rewound(&self.parent)?), 0),
+        };
+        let skip = (pos - dec_pos) as u64;
+        std::io::copy(&mut Read::by_ref(&mut dec).take(skip), &mut std::io::sink())?;
+        dec.read_exact(buf)?;
+        *self.zlib.borrow_mut() = Some((dec, pos + buf.len()));
+        Ok(())
+    }
+}
+
+impl Clone for ProcessedReader {
+    fn clone(&self) -> Self {
+        ProcessedReader {
+            state: self.state.clone(),
+            parent: Clone::clone(&self.parent),
+            process: self.process.clone(),
+            size: self.size,
+            zlib: RefCell::new(None),
+        }
+    }
+}
+
+impl fmt::Debug for ProcessedReader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ProcessedReader")
+            .field("state", &self.state)
+            .field("parent", &self.parent)
+            .field("process", &self.process)
+            .field("size", &self.size)
+            .finish()
+    }
+}
+
+impl KStream for ProcessedReader {
+    fn clone(&self) -> BytesReader {
+        clone_into_bytes_reader(self)
+    }
+
+    fn get_state(&self) -> Ref<'_, ReaderState> {
+        self.state.borrow()
+    }
+
+    fn get_state_mut(&self) -> RefMut<'_, ReaderState> {
+        self.state.borrow_mut()
+    }
+
+    fn size(&self) -> usize {
+        self.size
+    }
+
+    fn read_bytes_not_aligned_into(&self, buf: &mut [u8]) -> KResult<()> {
+        check_available(self, buf.len())?;
+        self.read_processed(buf, self.pos())?;
+        self.get_state_mut().pos += buf.len();
+        Ok(())
+    }
+}
+
+/// Return a byte array that is sized to exclude all trailing instances of the
+/// padding character.
+pub fn bytes_strip_right(bytes: &[u8], pad: u8) -> Vec<u8> {
+    if let Some(last_non_pad_index) = bytes.iter().rposition(|&c| c != pad) {
+        bytes[..=last_non_pad_index].to_vec()
+    } else {
+        vec![]
+    }
+}
+
+/// Return a byte array that contains all bytes up until the
+/// termination byte. Can optionally include the termination byte as well.
+pub fn bytes_terminate(bytes: &Vec<u8>, term: u8, include_term: bool) -> Vec<u8> {
+    if let Some(term_index) = bytes.iter().position(|&c| c == term) {
+        &bytes[..term_index + if include_term { 1 } else { 0 }]
+    } else {
+        bytes
+    }.to_vec()
+}
+
+/// How byte sequences that are invalid in the requested encoding are
+/// handled when decoding strings.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum DecodingPolicy {
+    /// Substitute U+FFFD REPLACEMENT CHARACTER (used by `bytes_to_str`).
+    #[default]
+    Replace,
+    /// Fail with `KError::BytesDecodingError` at the first invalid sequence.
+    Strict,
+    /// Drop invalid sequences.
+    Ignore,
+    /// Write each invalid byte as `\xNN`.
+    Escape,
+}
+
+pub fn bytes_to_str(bytes: &[u8], label: &str) -> KResult<String> {
+    bytes_to_str_with(bytes, label, DecodingPolicy::Replace)
+}
+
+/// `UTF-16` and `UTF-32` without an explicit endianness detect it from a
+/// byte order mark, which is stripped, and default to little endian.
+/// Input to any UTF-16/UTF-32 label whose length is not a multiple of the
+/// code unit size is rejected regardless of `policy`.
+pub fn bytes_to_str_with(bytes: &[u8], label: &str, policy: DecodingPolicy) -> KResult<String> {
+    if let Some(form) = unicode_form_from_label(label) {
+        return decode_unicode(bytes, form, policy);
+    }
+
+    if let Some(enc) = whatwg_encoding(label) {
+        return decode_with_policy(enc, bytes, policy);
+    }
+
+    if let Some(cp) = code_page_from_label(label) {
+        return cp.decode(bytes, policy);
+    }
+
+    if let Some(decoder) = registered_encoding(label) {
+        return decoder.decode(bytes, policy);
+    }
+
+    Err(KError::UnknownEncoding {
+        name: label.to_string(),
+    })
+}
+
+/// How characters that cannot be represented in the requested encoding are
+/// handled when encoding strings.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum EncodingPolicy {
+    /// Substitute `?` (used by `str_to_bytes`).
+    #[default]
+    Replace,
+    /// Fail with `KError::StrEncodingError` at the first unmappable character.
+    Strict,
+    /// Drop unmappable characters.
+    Ignore,
+}
+
+/// Encode `s` using the same labels `bytes_to_str` accepts.
+pub fn str_to_bytes(s: &str, label: &str) -> KResult<Vec<u8>> {
+    str_to_bytes_with(s, label, EncodingPolicy::Replace)
+}
+
+/// `UTF-16` and `UTF-32` without an explicit endianness are written as
+/// little endian, without a byte order mark.
+pub fn str_to_bytes_with(s: &str, label: &str, policy: EncodingPolicy) -> KResult<Vec<u8>> {
+    if let Some(form) = unicode_form_from_label(label) {
+        return Ok(encode_unicode(s, form));
+    }
+
+    if let Some(enc) = whatwg_encoding(label) {
+        return encode_with_policy(enc, s, policy);
+    }
+
+    if let Some(cp) = code_page_from_label(label) {
+        return cp.encode(s, policy);
+    }
+
+    Err(KError::UnknownEncoding {
+        name: label.to_string(),
+    })
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum UnicodeForm {
+    Utf16Le,
+    Utf16Be,
+    Utf32Le,
+    Utf32Be,
+    // endianness taken from the byte order mark, if any
+    Utf16Bom,
+    Utf32Bom,
+}
+
+impl UnicodeForm {
+    fn unit_size(self) -> usize {
+        match self {
+            UnicodeForm::Utf16Le | UnicodeForm::Utf16Be | UnicodeForm::Utf16Bom => 2,
+            UnicodeForm::Utf32Le | UnicodeForm::Utf32Be | UnicodeForm::Utf32Bom => 4,
+        }
+    }
+}
+
+fn unicode_form_from_label(label: &str) -> Option<UnicodeForm> {
+    match label.trim().to_ascii_lowercase().as_str() {
+        "utf-16" | "utf16" => Some(UnicodeForm::Utf16Bom),
+        "utf-16le" | "utf16le" => Some(UnicodeForm::Utf16Le),
+        "utf-16be" | "utf16be" => Some(UnicodeForm::Utf16Be),
+        "utf-32" | "utf32" => Some(UnicodeForm::Utf32Bom),
+        "utf-32le" | "utf32le" => Some(UnicodeForm::Utf32Le),
+        "utf-32be" | "utf32be" => Some(UnicodeForm::Utf32Be),
+        _ => None,
+    }
+}
+
+fn decode_unicode(bytes: &[u8], form: UnicodeForm, policy: DecodingPolicy) -> KResult<String> {
+    let unit = form.unit_size();
+    if !bytes.len().is_multiple_of(unit) {
+        return Err(KError::BytesDecodingError {
+            msg: format!("length {} is not a multiple of {}", bytes.len(), unit),
+            offset: bytes.len() - bytes.len() % unit,
+        });
+    }
+    let (form, bom_len) = match form {
+        UnicodeForm::Utf16Bom => match bytes {
+            [0xff, 0xfe, ..] => (UnicodeForm::Utf16Le, 2),
+            [0xfe, 0xff, ..] => (UnicodeForm::Utf16Be, 2),
+            _ => (UnicodeForm::Utf16Le, 0),
+        },
+        UnicodeForm::Utf32Bom => match bytes {
+            [0xff, 0xfe, 0, 0, ..] => (UnicodeForm::Utf32Le, 4),
+            [0, 0, 0xfe, 0xff, ..] => (UnicodeForm::Utf32Be, 4),
+            _ => (UnicodeForm::Utf32Le, 0),
+        },
+        _ => (form, 0),
+    };
+    let body = &bytes[bom_len..];
+    let res = match form {
+        UnicodeForm::Utf16Le => decode_with_policy(encoding::all::UTF_16LE, body, policy),
+        UnicodeForm::Utf16Be => decode_with_policy(encoding::all::UTF_16BE, body, policy),
+        _ => decode_utf32(body, form == UnicodeForm::Utf32Be, policy),
+    };
+    // report offsets relative to the input, not to the text after the BOM
+    res.map_err(|e| match e {
+        KError::BytesDecodingError { msg, offset } => KError::BytesDecodingError {
+            msg,
+            offset: offset + bom_len,
+        },
+        e => e,
+    })
+}
+
+fn decode_utf32(bytes: &[u8], big_endian: bool, policy: DecodingPolicy) -> KResult<String> {
+    let mut res = String::with_capacity(bytes.len() / 4);
+    for (i, unit) in bytes.chunks_exact(4).enumerate() {
+        let unit: [u8; 4] = unit.try_into().unwrap();
+        let code = if big_endian {
+            u32::from_be_bytes(unit)
+        } else {
+            u32::from_le_bytes(unit)
+        };
+        match (char::from_u32(code), policy) {
+            (Some(c), _) => res.push(c),
+            (None, DecodingPolicy::Replace) => res.push('\u{fffd}'),
+            (None, DecodingPolicy::Ignore) => {}
+            (None, DecodingPolicy::Escape) => {
+                for b in &unit {
+                    res.push_str(&format!("\\x{:02x}", b));
+                }
+            }
+            (None, DecodingPolicy::Strict) => {
+                return Err(KError::BytesDecodingError {
+                    msg: format!("invalid code point {:#x}", code),
+                    offset: i * 4,
+                })
+            }
+        }
+    }
+    Ok(res)
+}
+
+fn encode_unicode(s: &str, form: UnicodeForm) -> Vec<u8> {
+    match form {
+        UnicodeForm::Utf16Le | UnicodeForm::Utf16Bom => {
+            s.encode_utf16().flat_map(u16::to_le_bytes).collect()
+        }
+        UnicodeForm::Utf16Be => s.encode_utf16().flat_map(u16::to_be_bytes).collect(),
+        UnicodeForm::Utf32Le | UnicodeForm::Utf32Bom => {
+            s.chars().flat_map(|c| u32::from(c).to_le_bytes()).collect()
+        }
+        UnicodeForm::Utf32Be => s.chars().flat_map(|c| u32::from(c).to_be_bytes()).collect(),
+    }
+}
+
+type RegisteredEncoding = Arc<dyn CustomStrDecoder + Send + Sync>;
+
+static REGISTERED_ENCODINGS: RwLock<Vec<(String, RegisteredEncoding)>> = RwLock::new(Vec::new());
+
+/// Make `bytes_to_str` accept `name` (case-insensitively) as an encoding
+/// label. Built-in labels take precedence; registering a name again
+/// replaces the previous decoder.
+pub fn register_encoding<D>(name: &str, decoder: D)
+where
+    D: CustomStrDecoder + Send + Sync + 'static,
+{
+    let mut encodings = REGISTERED_ENCODINGS.write().unwrap();
+    encodings.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+    encodings.push((name.to_string(), Arc::new(decoder)));
+}
+
+/// Remove an encoding added with `register_encoding`. Returns whether it
+/// was registered.
+pub fn unregister_encoding(name: &str) -> bool {
+    let mut encodings = REGISTERED_ENCODINGS.write().unwrap();
+    let len = encodings.len();
+    encodings.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+    encodings.len() != len
+}
+
+fn registered_encoding(label: &str) -> Option<RegisteredEncoding> {
+    let label = label.trim();
+    // clone it out so the decoder runs without holding the lock
+    REGISTERED_ENCODINGS
+        .read()
+        .unwrap()
+        .iter()
+        .find(|(n, _)| n.eq_ignore_ascii_case(label))
+        .map(|(_, d)| Arc::clone(d))
+}
+
+// labels the WHATWG set lacks for encodings it does have
+const WHATWG_ALIASES: &[(&str, &str)] = &[
+    ("macroman", "macintosh"),
+    ("mac-roman", "macintosh"),
+    ("mac_roman", "macintosh"),
+    ("ibm-866", "ibm866"),
+    ("ms-cyrl", "windows-1251"),
+];
+
+fn whatwg_encoding(label: &str) -> Option<EncodingRef> {
+    encoding_from_whatwg_label(label).or_else(|| {
+        let label = label.trim();
+        WHATWG_ALIASES
+            .iter()
+            .find(|(alias, _)| alias.eq_ignore_ascii_case(label))
+            .and_then(|(_, whatwg)| encoding_from_whatwg_label(whatwg))
+    })
+}
+
+/// Single-byte code page outside of the WHATWG set. A 128-entry table only
+/// covers the upper half, the lower half being ASCII; U+FFFD marks bytes
+/// without a mapping.
+struct CodePage {
+    labels: &'static [&'static str],
+    table: &'static [char],
+}
+
+const CODE_PAGES: &[CodePage] = &[
+    CodePage {
+        labels: &["cp437", "ibm437", "437"],
+        table: &CP437_HIGH,
+    },
+    CodePage {
+        labels: &["cp850", "ibm850", "850"],
+        table: &CP850_HIGH,
+    },
+    CodePage {
+        labels: &["cp852", "ibm852", "852"],
+        table: &CP852_HIGH,
+    },
+    CodePage {
+        labels: &["cp037", "ibm037", "ebcdic-cp-us", "037"],
+        table: &CP037,
+    },
+    CodePage {
+        labels: &["cp500", "ibm500", "ebcdic-cp-be", "500"],
+        table: &CP500,
+    },
+    CodePage {
+        labels: &["cp1047", "ibm1047", "1047"],
+        table: &CP1047,
+    },
+    CodePage {
+        labels: &["petscii"],
+        table: &PETSCII,
+    },
+    CodePage {
+        labels: &["atascii"],
+        table: &ATASCII,
+    },
+];
+
+fn code_page_from_label(label: &str) -> Option<&'static CodePage> {
+    let label = label.trim();
+    CODE_PAGES
+        .iter()
+        .find(|cp| cp.labels.iter().any(|l| l.eq_ignore_ascii_case(label)))
+}
+
+impl CodePage {
+    fn is_ascii_compatible(&self) -> bool {
+        self.table.len() == 128
+    }
+
+    fn decode_byte(&self, b: u8) -> Option<char> {
+        let c = match self.table.len() {
+            128 if b < 0x80 => b as char,
+            128 => self.table[usize::from(b - 0x80)],
+            _ => self.table[usize::from(b)],
+        };
+        if c == '\u{fffd}' {
+            None
+        } else {
+            Some(c)
+        }
+    }
+
+    fn encode_char(&self, c: char) -> Option<u8> {
+        if c == '\u{fffd}' {
+            return None;
+        }
+        if self.is_ascii_compatible() {
+            if c.is_ascii() {
+                return Some(c as u8);
+            }
+            return self.table.iter().position(|&h| h == c).map(|i| 0x80 + i as u8);
+        }
+        self.table.iter().position(|&h| h == c).map(|i| i as u8)
+    }
+
+    fn decode(&self, bytes: &[u8], policy: DecodingPolicy) -> KResult<String> {
+        let mut res = String::with_capacity(bytes.len());
+        for (offset, &b) in bytes.iter().enumerate() {
+            match (self.decode_byte(b), policy) {
+                (Some(c), _) => res.push(c),
+                (None, DecodingPolicy::Replace) => res.push('\u{fffd}'),
+                (None, DecodingPolicy::Ignore) => {}
+                (None, DecodingPolicy::Escape) => res.push_str(&format!("\\x{:02x}", b)),
+                (None, DecodingPolicy::Strict) => {
+                    return Err(KError::BytesDecodingError {
+                        msg: format!("byte {:#04x} has no mapping", b),
+                        offset,
+                    })
+                }
+            }
+        }
+        Ok(res)
+    }
+
+    fn encode(&self, s: &str, policy: EncodingPolicy) -> KResult<Vec<u8>> {
+        let mut res = Vec::with_capacity(s.len());
+        for (offset, c) in s.char_indices() {
+            match (self.encode_char(c), policy) {
+                (Some(b), _) => res.push(b),
+                (None, EncodingPolicy::Replace) => res.push(self.encode_char('?').unwrap()),
+                (None, EncodingPolicy::Ignore) => {}
+                (None, EncodingPolicy::Strict) => {
+                    return Err(KError::StrEncodingError {
+                        msg: format!("unrepresentable character {:?}", c),
+                        offset,
+                    })
+                }
+            }
+        }
+        Ok(res)
+    }
+}
+
+// upper half of code page 437; the lower half is ASCII
+#[rustfmt::skip]
+const CP437_HIGH: [char; 128] = [
+    'Ç', 'ü', 'é', 'â', 'ä', 'à', 'å', 'ç', 'ê', 'ë', 'è', 'ï', 'î', 'ì', 'Ä', 'Å',
+    'É', 'æ', 'Æ', 'ô', 'ö', 'ò', 'û', 'ù', 'ÿ', 'Ö', 'Ü', '¢', '£', '¥', '₧', 'ƒ',
+    'á', 'í', 'ó', 'ú', 'ñ', 'Ñ', 'ª', 'º', '¿', '⌐', '¬', '½', '¼', '¡', '«', '»',
+    '░', '▒', '▓', '│', '┤', '╡', '╢', '╖', '╕', '╣', '║', '╗', '╝', '╜', '╛', '┐',
+    '└', '┴', '┬', '├', '─', '┼', '╞', '╟', '╚', '╔', '╩', '╦', '╠', '═', '╬', '╧',
+    '╨', '╤', '╥', '╙', '╘', '╒', '╓', '╫', '╪', '┘', '┌', '█', '▄', '▌', '▐', '▀',
+    'α', 'ß', 'Γ', 'π', 'Σ', 'σ', 'µ', 'τ', 'Φ', 'Θ', 'Ω', 'δ', '∞', 'φ', 'ε', '∩',
+    '≡', '±', '≥', '≤', '⌠', '⌡', '÷', '≈', '°', '∙', '·', '√', 'ⁿ', '²', '■', '\u{a0}',
+];
+
+// upper half of code page 850 (DOS Latin-1)
+#[rustfmt::skip]
+const CP850_HIGH: [char; 128] = [
+    'Ç', 'ü', 'é', 'â', 'ä', 'à', 'å', 'ç', 'ê', 'ë', 'è', 'ï', 'î', 'ì', 'Ä', 'Å',
+    'É', 'æ', 'Æ', 'ô', 'ö', 'ò', 'û', 'ù', 'ÿ', 'Ö', 'Ü', 'ø', '£', 'Ø', '×', 'ƒ',
+    'á', 'í', 'ó', 'ú', 'ñ', 'Ñ', 'ª', 'º', '¿', '®', '¬', '½', '¼', '¡', '«', '»',
+    '░', '▒', '▓', '│', '┤', 'Á', 'Â', 'À', '©', '╣', '║', '╗', '╝', '¢', '¥', '┐',
+    '└', '┴', '┬', '├', '─', '┼', 'ã', 'Ã', '╚', '╔', '╩', '╦', '╠', '═', '╬', '¤',
+    'ð', 'Ð', 'Ê', 'Ë', 'È', 'ı', 'Í', 'Î', 'Ï', '┘', '┌', '█', '▄', '¦', 'Ì', '▀',
+    'Ó', 'ß', 'Ô', 'Ò', 'õ', 'Õ', 'µ', 'þ', 'Þ', 'Ú', 'Û', 'Ù', 'ý', 'Ý', '¯', '´',
+    '\u{ad}', '±', '‗', '¾', '¶', '§', '÷', '¸', '°', '¨', '·', '¹', '³', '²', '■', '\u{a0}',
+];
+
+// upper half of code page 852 (DOS Latin-2)
+#[rustfmt::skip]
+const CP852_HIGH: [char; 128] = [
+    'Ç', 'ü', 'é', 'â', 'ä', 'ů', 'ć', 'ç', 'ł', 'ë', 'Ő', 'ő', 'î', 'Ź', 'Ä', 'Ć',
+    'É', 'Ĺ', 'ĺ', 'ô', 'ö', 'Ľ', 'ľ', 'Ś', 'ś', 'Ö', 'Ü', 'Ť', 'ť', 'Ł', '×', 'č',
+    'á', 'í', 'ó', 'ú', 'Ą', 'ą', 'Ž', 'ž', 'Ę', 'ę', '¬', 'ź', 'Č', 'ş', '«', '»',
+    '░', '▒', '▓', '│', '┤', 'Á', 'Â', 'Ě', 'Ş', '╣', '║', '╗', '╝', 'Ż', 'ż', '┐',
+    '└', '┴', '┬', '├', '─', '┼', 'Ă', 'ă', '╚', '╔', '╩', '╦', '╠', '═', '╬', '¤',
+    'đ', 'Đ', 'Ď', 'Ë', 'ď', 'Ň', 'Í', 'Î', 'ě', '┘', '┌', '█', '▄', 'Ţ', 'Ů', '▀',
+    'Ó', 'ß', 'Ô', 'Ń', 'ń', 'ň', 'Š', 'š', 'Ŕ', 'Ú', 'ŕ', 'Ű', 'ý', 'Ý', 'ţ', '´',
+    '\u{ad}', '˝', '˛', 'ˇ', '˘', '§', '÷', '¸', '°', '¨', '˙', 'ű', 'Ř', 'ř', '■', '\u{a0}',
+];
+
+// EBCDIC code page 037 (US/Canada)
+#[rustfmt::skip]
+const CP037: [char; 256] = [
+    '\u{0}', '\u{1}', '\u{2}', '\u{3}', '\u{9c}', '\u{9}', '\u{86}', '\u{7f}', '\u{97}', '\u{8d}', '\u{8e}', '\u{b}', '\u{c}', '\u{d}', '\u{e}', '\u{f}',
+    '\u{10}', '\u{11}', '\u{12}', '\u{13}', '\u{9d}', '\u{85}', '\u{8}', '\u{87}', '\u{18}', '\u{19}', '\u{92}', '\u{8f}', '\u{1c}', '\u{1d}', '\u{1e}', '\u{1f}',
+    '\u{80}', '\u{81}', '\u{82}', '\u{83}', '\u{84}', '\u{a}', '\u{17}', '\u{1b}', '\u{88}', '\u{89}', '\u{8a}', '\u{8b}', '\u{8c}', '\u{5}', '\u{6}', '\u{7}',
+    '\u{90}', '\u{91}', '\u{16}', '\u{93}', '\u{94}', '\u{95}', '\u{96}', '\u{4}', '\u{98}', '\u{99}', '\u{9a}', '\u{9b}', '\u{14}', '\u{15}', '\u{9e}', '\u{1a}',
+    ' ', '\u{a0}', 'â', 'ä', 'à', 'á', 'ã', 'å', 'ç', 'ñ', '¢', '.', '<', '(', '+', '|',
+    '&', 'é', 'ê', 'ë', 'è', 'í', 'î', 'ï', 'ì', 'ß', '!', '$', '*', ')', ';', '¬',
+    '-', '/', 'Â', 'Ä', 'À', 'Á', 'Ã', 'Å', 'Ç', 'Ñ', '¦', ',', '%', '_', '>', '?',
+    'ø', 'É', 'Ê', 'Ë', 'È', 'Í', 'Î', 'Ï', 'Ì', '`', ':', '#', '@', '\'', '=', '"',
+    'Ø', 'a', 'b', 'c', 'd', 'e', 'f', 'g', 'h', 'i', '«', '»', 'ð', 'ý', 'þ', '±',
+    '°', 'j', 'k', 'l', 'm', 'n', 'o', 'p', 'q', 'r', 'ª', 'º', 'æ', '¸', 'Æ', '¤',
+    'µ', '~', 's', 't', 'u', 'v', 'w', 'x', 'y', 'z', '¡', '¿', 'Ð', 'Ý', 'Þ', '®',
+    '^', '£', '¥', '·', '©', '§', '¶', '¼', '½', '¾', '[', ']', '¯', '¨', '´', '×',
+    '{', 'A', 'B', 'C', 'D', 'E', 'F', 'G', 'H', 'I', '\u{ad}', 'ô', 'ö', 'ò', 'ó', 'õ',
+    '}', 'J', 'K', 'L', 'M', 'N', 'O', 'P', 'Q', 'R', '¹', 'û', 'ü', 'ù', 'ú', 'ÿ',
+    '\\', '÷', 'S', 'T', 'U', 'V', 'W', 'X', 'Y', 'Z', '²', 'Ô', 'Ö', 'Ò', 'Ó', 'Õ',
+    '0', '1', '2', '3', '4', '5', '6', '7', '8', '9', '³', 'Û', 'Ü', 'Ù', 'Ú', '\u{9f}',
+];
+
+// EBCDIC code page 500 (International)
+#[rustfmt::skip]
+const CP500: [char; 256] = [
+    '\u{0}', '\u{1}', '\u{2}', '\u{3}', '\u{9c}', '\u{9}', '\u{86}', '\u{7f}', '\u{97}', '\u{8d}', '\u{8e}', '\u{b}', '\u{c}', '\u{d}', '\u{e}', '\u{f}',
+    '\u{10}', '\u{11}', '\u{12}', '\u{13}', '\u{9d}', '\u{85}', '\u{8}', '\u{87}', '\u{18}', '\u{19}', '\u{92}', '\u{8f}', '\u{1c}', '\u{1d}', '\u{1e}', '\u{1f}',
+    '\u{80}', '\u{81}', '\u{82}', '\u{83}', '\u{84}', '\u{a}', '\u{17}', '\u{1b}', '\u{88}', '\u{89}', '\u{8a}', '\u{8b}', '\u{8c}', '\u{5}', '\u{6}', '\u{7}',
+    '\u{90}', '\u{91}', '\u{16}', '\u{93}', '\u{94}', '\u{95}', '\u{96}', '\u{4}', '\u{98}', '\u{99}', '\u{9a}', '\u{9b}', '\u{14}', '\u{15}', '\u{9e}', '\u{1a}',
+    ' ', '\u{a0}', 'â', 'ä', 'à', 'á', 'ã', 'å', 'ç', 'ñ', '[', '.', '<', '(', '+', '!',
+    '&', 'é', 'ê', 'ë', 'è', 'í', 'î', 'ï', 'ì', 'ß', ']', '$', '*', ')', ';', '^',
+    '-', '/', 'Â', 'Ä', 'À', 'Á', 'Ã', 'Å', 'Ç', 'Ñ', '¦', ',', '%', '_', '>', '?',
+    'ø', 'É', 'Ê', 'Ë', 'È', 'Í', 'Î', 'Ï', 'Ì', '`', ':', '#', '@', '\'', '=', '"',
+    'Ø', 'a', 'b', 'c', 'd', 'e', 'f', 'g', 'h', 'i', '«', '»', 'ð', 'ý', 'þ', '±',
+    '°', 'j', 'k', 'l', 'm', 'n', 'o', 'p', 'q', 'r', 'ª', 'º', 'æ', '¸', 'Æ', '¤',
+    'µ', '~', 's', 't', 'u', 'v', 'w', 'x', 'y', 'z', '¡', '¿', 'Ð', 'Ý', 'Þ', '®',
+    '¢', '£', '¥', '·', '©', '§', '¶', '¼', '½', '¾', '¬', '|', '¯', '¨', '´', '×',
+    '{', 'A', 'B', 'C', 'D', 'E', 'F', 'G', 'H', 'I', '\u{ad}', 'ô', 'ö', 'ò', 'ó', 'õ',
+    '}', 'J', 'K', 'L', 'M', 'N', 'O', 'P', 'Q', 'R', '¹', 'û', 'ü', 'ù', 'ú', 'ÿ',
+    '\\', '÷', 'S', 'T', 'U', 'V', 'W', 'X', 'Y', 'Z', '²', 'Ô', 'Ö', 'Ò', 'Ó', 'Õ',
+    '0', '1', '2', '3', '4', '5', '6', '7', '8', '9', '³', 'Û', 'Ü', 'Ù', 'Ú', '\u{9f}',
+];
+
+// EBCDIC code page 1047 (Latin-1/Open Systems)
+#[rustfmt::skip]
+const CP1047: [char; 256] = [
+    '\u{0}', '\u{1}', '\u{2}', '\u{3}', '\u{9c}', '\u{9}', '\u{86}', '\u{7f}', '\u{97}', '\u{8d}', '\u{8e}', '\u{b}', '\u{c}', '\u{d}', '\u{e}', '\u{f}',
+    '\u{10}', '\u{11}', '\u{12}', '\u{13}', '\u{9d}', '\u{85}', '\u{8}', '\u{87}', '\u{18}', '\u{19}', '\u{92}', '\u{8f}', '\u{1c}', '\u{1d}', '\u{1e}', '\u{1f}',
+    '\u{80}', '\u{81}', '\u{82}', '\u{83}', '\u{84}', '\u{a}', '\u{17}', '\u{1b}', '\u{88}', '\u{89}', '\u{8a}', '\u{8b}', '\u{8c}', '\u{5}', '\u{6}', '\u{7}',
+    '\u{90}', '\u{91}', '\u{16}', '\u{93}', '\u{94}', '\u{95}', '\u{96}', '\u{4}', '\u{98}', '\u{99}', '\u{9a}', '\u{9b}', '\u{14}', '\u{15}', '\u{9e}', '\u{1a}',
+    ' ', '\u{a0}', 'â', 'ä', 'à', 'á', 'ã', 'å', 'ç', 'ñ', '¢', '.', '<', '(', '+', '|',
+    '&', 'é', 'ê', 'ë', 'è', 'í', 'î', 'ï', 'ì', 'ß', '!', '$', '*', ')', ';', '^',
+    '-', '/', 'Â', 'Ä', 'À', 'Á', 'Ã', 'Å', 'Ç', 'Ñ', '¦', ',', '%', '_', '>', '?',
+    'ø', 'É', 'Ê', 'Ë', 'È', 'Í', 'Î', 'Ï', 'Ì', '`', ':', '#', '@', '\'', '=', '"',
+    'Ø', 'a', 'b', 'c', 'd', 'e', 'f', 'g', 'h', 'i', '«', '»', 'ð', 'ý', 'þ', '±',
+    '°', 'j', 'k', 'l', 'm', 'n', 'o', 'p', 'q', 'r', 'ª', 'º', 'æ', '¸', 'Æ', '¤',
+    'µ', '~', 's', 't', 'u', 'v', 'w', 'x', 'y', 'z', '¡', '¿', 'Ð', '[', 'Þ', '®',
+    '¬', '£', '¥', '·', '©', '§', '¶', '¼', '½', '¾', 'Ý', '¨', '¯', ']', '´', '×',
+    '{', 'A', 'B', 'C', 'D', 'E', 'F', 'G', 'H', 'I', '\u{ad}', 'ô', 'ö', 'ò', 'ó', 'õ',
+    '}', 'J', 'K', 'L', 'M', 'N', 'O', 'P', 'Q', 'R', '¹', 'û', 'ü', 'ù', 'ú', 'ÿ',
+    '\\', '÷', 'S', 'T', 'U', 'V', 'W', 'X', 'Y', 'Z', '²', 'Ô', 'Ö', 'Ò', 'Ó', 'Õ',
+    '0', '1', '2', '3', '4', '5', '6', '7', '8', '9', '³', 'Û', 'Ü', 'Ù', 'Ú', '\u{9f}',
+];
+
+// PETSCII, Commodore 64 unshifted (upper case/graphics) set;
+// control codes other than return are undefined
+#[rustfmt::skip]
+const PETSCII: [char; 256] = [
+    '�', '�', '�', '�', '�', '�', '�', '�', '�', '�', '�', '�', '�', '\u{d}', '�', '�',
+    '�', '�', '�', '�', '�', '�', '�', '�', '�', '�', '�', '�', '�', '�', '�', '�',
+    ' ', '!', '"', '#', '$', '%', '&', '\'', '(', ')', '*', '+', ',', '-', '.', '/',
+    '0', '1', '2', '3', '4', '5', '6', '7', '8', '9', ':', ';', '<', '=', '>', '?',
+    '@', 'A', 'B', 'C', 'D', 'E', 'F', 'G', 'H', 'I', 'J', 'K', 'L', 'M', 'N', 'O',
+    'P', 'Q', 'R', 'S', 'T', 'U', 'V', 'W', 'X', 'Y', 'Z', '[', '£', ']', '↑', '←',
+    '─', '♠', '🭲', '🭸', '🭷', '🭶', '🭺', '🭱', '🭴', '╮', '╰', '╯', '🭼', '╲', '╱', '🭽',
+    '🭾', '●', '🭻', '♥', '🭰', '╭', '╳', '○', '♣', '🭵', '♦', '┼', '🮌', '│', 'π', '◥',
+    '�', '�', '�', '�', '�', '�', '�', '�', '�', '�', '�', '�', '�', '\u{a}', '�', '�',
+    '�', '�', '�', '�', '�', '�', '�', '�', '�', '�', '�', '�', '�', '�', '�', '�',
+    '\u{a0}', '▌', '▄', '▔', '▁', '▏', '▒', '▕', '🮏', '◤', '🮇', '├', '▗', '└', '┐', '▂',
+    '┌', '┴', '┬', '┤', '▎', '▍', '🮈', '🮂', '🮃', '▃', '🭿', '▖', '▝', '┘', '▘', '▚',
+    '─', '♠', '🭲', '🭸', '🭷', '🭶', '🭺', '🭱', '🭴', '╮', '╰', '╯', '🭼', '╲', '╱', '🭽',
+    '🭾', '●', '🭻', '♥', '🭰', '╭', '╳', '○', '♣', '🭵', '♦', '┼', '🮌', '│', 'π', '◥',
+    '\u{a0}', '▌', '▄', '▔', '▁', '▏', '▒', '▕', '🮏', '◤', '🮇', '├', '▗', '└', '┐', '▂',
+    '┌', '┴', '┬', '┤', '▎', '▍', '🮈', '🮂', '🮃', '▃', '🭿', '▖', '▝', '┘', '▘', 'π',
+];
+
+// ATASCII (Atari 8-bit); inverse video characters in the upper half
+// decode to their normal counterparts, except 0x9b which is end of line
+#[rustfmt::skip]
+const ATASCII: [char; 256] = [
+    '♥', '├', '🮇', '┘', '┤', '┐', '╱', '╲', '◢', '▗', '◣', '▝', '▘', '🮂', '▁', '▖',
+    '♣', '┌', '─', '┼', '●', '▄', '▎', '┬', '┴', '▌', '└', '␛', '↑', '↓', '←', '→',
+    ' ', '!', '"', '#', '$', '%', '&', '\'', '(', ')', '*', '+', ',', '-', '.', '/',
+    '0', '1', '2', '3', '4', '5', '6', '7', '8', '9', ':', ';', '<', '=', '>', '?',
+    '@', 'A', 'B', 'C', 'D', 'E', 'F', 'G', 'H', 'I', 'J', 'K', 'L', 'M', 'N', 'O',
+    'P', 'Q', 'R', 'S', 'T', 'U', 'V', 'W', 'X', 'Y', 'Z', '[', '\\', ']', '^', '_',
+    '◆', 'a', 'b', 'c', 'd', 'e', 'f', 'g', 'h', 'i', 'j', 'k', 'l', 'm', 'n', 'o',
+    'p', 'q', 'r', 's', 't', 'u', 'v', 'w', 'x', 'y', 'z', '♠', '|', '↰', '◀', '▶',
+    '♥', '├', '🮇', '┘', '┤', '┐', '╱', '╲', '◢', '▗', '◣', '▝', '▘', '🮂', '▁', '▖',
+    '♣', '┌', '─', '┼', '●', '▄', '▎', '┬', '┴', '▌', '└', '\u{a}', '↑', '↓', '←', '→',
+    ' ', '!', '"', '#', '$', '%', '&', '\'', '(', ')', '*', '+', ',', '-', '.', '/',
+    '0', '1', '2', '3', '4', '5', '6', '7', '8', '9', ':', ';', '<', '=', '>', '?',
+    '@', 'A', 'B', 'C', 'D', 'E', 'F', 'G', 'H', 'I', 'J', 'K', 'L', 'M', 'N', 'O',
+    'P', 'Q', 'R', 'S', 'T', 'U', 'V', 'W', 'X', 'Y', 'Z', '[', '\\', ']', '^', '_',
+    '◆', 'a', 'b', 'c', 'd', 'e', 'f', 'g', 'h', 'i', 'j', 'k', 'l', 'm', 'n', 'o',
+    'p', 'q', 'r', 's', 't', 'u', 'v', 'w', 'x', 'y', 'z', '♠', '|', '↰', '◀', '▶',
+];
+
+// Same loop as `Encoding::encode_to`, but it keeps track of the offset
+// of each unmappable character so that it can be reported.
+fn encode_with_policy(enc: EncodingRef, s: &str, policy: EncodingPolicy) -> KResult<Vec<u8>> {
+    let mut encoder = enc.raw_encoder();
+    let mut res = Vec::with_capacity(s.len());
+    let mut remaining = 0;
+    loop {
+        let (offset, err) = encoder.raw_feed(&s[remaining..], &mut res);
+        let unprocessed = remaining + offset;
+        let err = match err {
+            Some(err) => err,
+            None => match encoder.raw_finish(&mut res) {
+                Some(err) => err,
+                None => return Ok(res),
+            },
+        };
+        remaining = (remaining as isize + err.upto) as usize;
+        match policy {
+            EncodingPolicy::Replace => {
+                // '?' goes through the encoder, it is not one byte everywhere
+                encoder.raw_feed("?", &mut res);
+            }
+            EncodingPolicy::Ignore => {}
+            EncodingPolicy::Strict => {
+                return Err(KError::StrEncodingError {
+                    msg: err.cause.into_owned(),
+                    offset: unprocessed,
+                })
+            }
+        }
+        if remaining >= s.len() {
+            return Ok(res);
+        }
+    }
+}
+
+/// Like `bytes_to_str`, but borrows from `bytes` instead of allocating when
+/// no transcoding is needed: valid UTF-8 for a UTF-8 label, or pure ASCII
+/// for an ASCII-compatible encoding.
+pub fn bytes_to_str_cow<'a>(bytes: &'a [u8], label: &str) -> KResult<Cow<'a, str>> {
+    bytes_to_str_cow_with(bytes, label, DecodingPolicy::Replace)
+}
+
+pub fn bytes_to_str_cow_with<'a>(
+    bytes: &'a [u8],
+    label: &str,
+    policy: DecodingPolicy,
+) -> KResult<Cow<'a, str>> {
+    let borrowable = if unicode_form_from_label(label).is_some() {
+        false
+    } else if let Some(enc) = whatwg_encoding(label) {
+        if enc.name() == "utf-8" {
+            if let Ok(s) = std::str::from_utf8(bytes) {
+                return Ok(Cow::Borrowed(s));
+            }
+            false
+        } else {
+            bytes.is_ascii() && enc.raw_decoder().is_ascii_compatible()
+        }
+    } else if let Some(cp) = code_page_from_label(label) {
+        cp.is_ascii_compatible() && bytes.is_ascii()
+    } else {
+        false
+    };
+    if borrowable {
+        // ASCII is valid UTF-8
+        return Ok(Cow::Borrowed(std::str::from_utf8(bytes).unwrap()));
+    }
+    bytes_to_str_with(bytes, label, policy).map(Cow::Owned)
+}
+
+// Decode a buffer we own, reusing its allocation when it already holds
+// the decoded text.
+fn owned_bytes_to_str(bytes: Vec<u8>, label: &str) -> KResult<String> {
+    let decoded = match bytes_to_str_cow(&bytes, label)? {
+        Cow::Borrowed(_) => None,
+        Cow::Owned(s) => Some(s),
+    };
+    Ok(decoded.unwrap_or_else(|| String::from_utf8(bytes).unwrap()))
+}
+
+// Same loop as `Encoding::decode_to`, but it keeps track of the offset
+// of each invalid sequence so that it can be reported or escaped.
+fn decode_with_policy(enc: EncodingRef, bytes: &[u8], policy: DecodingPolicy) -> KResult<String> {
+    let mut decoder = enc.raw_decoder();
+    let mut res = String::with_capacity(bytes.len());
+    let mut remaining = 0;
+    loop {
+        let (offset, err) = decoder.raw_feed(&bytes[remaining..], &mut res);
+        let unprocessed = remaining + offset;
+        let (err, finished) = match err {
+            Some(err) => (err, false),
+            None => {
+                remaining = bytes.len();
+                match decoder.raw_finish(&mut res) {
+                    Some(err) => (err, true),
+                    None => return Ok(res),
+                }
+            }
+        };
+        remaining = (remaining as isize + err.upto) as usize;
+        match policy {
+            DecodingPolicy::Replace => res.push('\u{fffd}'),
+            DecodingPolicy::Ignore => {}
+            DecodingPolicy::Escape => {
+                for b in &bytes[unprocessed..remaining] {
+                    res.push_str(&format!("\\x{:02x}", b));
+                }
+            }
+            DecodingPolicy::Strict => {
+                return Err(KError::BytesDecodingError {
+                    msg: err.cause.into_owned(),
+                    offset: unprocessed,
+                })
+            }
+        }
+        if finished && remaining >= bytes.len() {
+            return Ok(res);
+        }
+    }
+}
+
+pub fn process_xor_one(bytes: &[u8], key: u8) -> Vec<u8> {
+    let mut res = bytes.to_vec();
+    for i in &mut res {
+        *i ^= key;
+    }
+    res
+}
+
+pub fn process_xor_many(bytes: &[u8], key: &[u8]) -> Vec<u8> {
+    let mut res = bytes.to_vec();
+    let mut ki = 0;
+    for i in &mut res {
+        *i ^= key[ki];
+        ki += 1;
+        if ki >= key.len() {
+            ki = 0;
+        }
+    }
+    res
+}
+
+pub fn process_rotate_left(bytes: &[u8], amount: u8) -> Vec<u8> {
+    let mut res = bytes.to_vec();
+    for i in &mut res {
+        *i = i.rotate_left(amount.into());
+    }
+    res
+}
+
+pub fn process_rotate_right(bytes: &[u8], amount: u8) -> Vec<u8> {
+    let mut res = bytes.to_vec();
+    for i in &mut res {
+        *i = i.rotate_right(amount.into());
+    }
+    res
+}
+
+/// Rotate each `group_size`-byte unit (1, 2, 4 or 8) left by `amount` bits,
+/// reading the units with the given endianness. The length must be a
+/// multiple of `group_size`.
+pub fn process_rotate_left_group(
+    bytes: &[u8],
+    amount: u32,
+    group_size: usize,
+    big_endian: bool,
+) -> Result<Vec<u8>, String> {
+    rotate_groups(bytes, amount, group_size, big_endian, false)
+}
+
+/// Like `process_rotate_left_group`, but rotating right.
+pub fn process_rotate_right_group(
+    bytes: &[u8],
+    amount: u32,
+    group_size: usize,
+    big_endian: bool,
+) -> Result<Vec<u8>, String> {
+    rotate_groups(bytes, amount, group_size, big_endian, true)
+}
+
+fn rotate_groups(
+    bytes: &[u8],
+    amount: u32,
+    group_size: usize,
+    big_endian: bool,
+    right: bool,
+) -> Result<Vec<u8>, String> {
+    if !matches!(group_size, 1 | 2 | 4 | 8) {
+        return Err(format!("unsupported rotation group size {}", group_size));
+    }
+    if !bytes.len().is_multiple_of(group_size) {
+        return Err(format!(
+            "length {} is not a multiple of group size {}",
+            bytes.len(),
+            group_size
+        ));
+    }
+    let bits = group_size as u32 * 8;
+    let mask = u64::MAX >> (64 - bits);
+    let amount = amount % bits;
+    let amount = if right {
+        (bits - amount) % bits
+    } else {
+        amount
+    };
+    let mut res = Vec::with_capacity(bytes.len());
+    for group in bytes.chunks_exact(group_size) {
+        let mut v = if big_endian {
+            group.iter().fold(0u64, |v, &b| v << 8 | u64::from(b))
+        } else {
+            group.iter().rev().fold(0u64, |v, &b| v << 8 | u64::from(b))
+        };
+        if amount != 0 {
+            v = (v << amount | v >> (bits - amount)) & mask;
+        }
+        if big_endian {
+            res.extend_from_slice(&v.to_be_bytes()[8 - group_size..]);
+        } else {
+            res.extend_from_slice(&v.to_le_bytes()[..group_size]);
+        }
+    }
+    Ok(res)
+}
+
+pub fn process_zlib(bytes: &[u8]) -> Result<Vec<u8>, String> {
+    let mut dec = ZlibDecoder::new(bytes);
+    let mut dec_bytes = Vec::new();
+    dec.read_to_end(&mut dec_bytes).map_err(|e| e.to_string())?;
+    Ok(dec_bytes)
+}
+
+fn text_decoding_error(msg: &str, offset: usize) -> KError {
+    KError::BytesDecodingError {
+        msg: msg.to_string(),
+        offset,
+    }
+}
+
+/// Decodes base64, skipping ASCII whitespace such as the line breaks of
+/// PEM and MIME. `url_safe` selects the `-_` alphabet instead of `+/`.
+/// With `padded`, the input must be padded with `=` to a multiple of 4
+/// characters; without it, `=` is not allowed.
+pub fn process_base64(bytes: &[u8], url_safe: bool, padded: bool) -> KResult<Vec<u8>> {
+    let (c62, c63) = if url_safe { (b'-', b'_') } else { (b'+', b'/') };
+    let mut res = Vec::with_capacity(bytes.len() / 4 * 3);
+    let mut acc = 0u32;
+    let mut n = 0;
+    let mut pad = 0;
+    for (i, &c) in bytes.iter().enumerate() {
+        let v = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            _ if c == c62 => 62,
+            _ if c == c63 => 63,
+            b'=' if padded && n >= 2 => {
+                pad += 1;
+                n += 1;
+                if n == 4 {
+                    n = 0;
+                }
+                continue;
+            }
+            _ if c.is_ascii_whitespace() => continue,
+            _ => return Err(text_decoding_error("invalid base64 character", i)),
+        };
+        if pad > 0 {
+            return Err(text_decoding_error("base64 data after padding", i));
+        }
+        acc = (acc << 6) | u32::from(v);
+        n += 1;
+        if n == 4 {
+            res.extend_from_slice(&acc.to_be_bytes()[1..]);
+            acc = 0;
+            n = 0;
+        }
+    }
+    if padded && n != 0 {
+        return Err(text_decoding_error("missing base64 padding", bytes.len()));
+    }
+    let n = if padded { (4 - pad) % 4 } else { n };
+    match n {
+        0 => {}
+        2 => res.push((acc >> 4) as u8),
+        3 => res.extend_from_slice(&((acc >> 2) as u16).to_be_bytes()),
+        _ => return Err(text_decoding_error("truncated base64 data", bytes.len())),
+    }
+    Ok(res)
+}
+
+/// Decodes hexadecimal digits (either case), skipping ASCII whitespace.
+pub fn process_hex(bytes: &[u8]) -> KResult<Vec<u8>> {
+    let mut res = Vec::with_capacity(bytes.len() / 2);
+    let mut high = None;
+    for (i, &c) in bytes.iter().enumerate() {
+        if c.is_ascii_whitespace() {
+            continue;
+        }
+        let v = (c as char)
+            .to_digit(16)
+            .ok_or_else(|| text_decoding_error("invalid hex digit", i))? as u8;
+        match high.take() {
+            None => high = Some((v, i)),
+            Some((h, _)) => res.push((h << 4) | v),
+        }
+    }
+    match high {
+        Some((_, i)) => Err(text_decoding_error("odd number of hex digits", i)),
+        None => Ok(res),
+    }
+}
+
+/// Decodes Adobe ASCII85, with or without the `<~` `~>` delimiters.
+/// Whitespace is skipped and `z` stands for four zero bytes.
+pub fn process_ascii85(bytes: &[u8]) -> KResult<Vec<u8>> {
+    let start = if bytes.starts_with(b"<~") { 2 } else { 0 };
+    let mut res = Vec::with_capacity(bytes.len() / 5 * 4);
+    let mut acc = 0u64;
+    let mut n = 0;
+    let mut end = bytes.len();
+    for (i, &c) in bytes.iter().enumerate().skip(start) {
+        match c {
+            b'!'..=b'u' => {
+                acc = acc * 85 + u64::from(c - b'!');
+                n += 1;
+                if n == 5 {
+                    let v: u32 = acc
+                        .try_into()
+                        .map_err(|_| text_decoding_error("ASCII85 group out of range", i))?;
+                    res.extend_from_slice(&v.to_be_bytes());
+                    acc = 0;
+                    n = 0;
+                }
+            }
+            b'z' if n == 0 => res.extend_from_slice(&[0; 4]),
+            b'~' if bytes.get(i + 1) == Some(&b'>') => {
+                end = i;
+                break;
+            }
+            _ if c.is_ascii_whitespace() => {}
+            _ => return Err(text_decoding_error("invalid ASCII85 character", i)),
+        }
+    }
+    match n {
+        0 => {}
+        1 => return Err(text_decoding_error("truncated ASCII85 group", end)),
+        _ => {
+            // pad the final group with `u`, then drop as many bytes as
+            // characters were missing
+            for _ in n..5 {
+                acc = acc * 85 + 84;
+            }
+            let v: u32 = acc
+                .try_into()
+                .map_err(|_| text_decoding_error("ASCII85 group out of range", end))?;
+            res.extend_from_slice(&v.to_be_bytes()[..n - 1]);
+        }
+    }
+    Ok(res)
+}
+
+/// Decodes uuencoded lines. The `begin` header and `end` trailer are
+/// optional; decoding stops at the first line of length zero.
+pub fn process_uuencode(bytes: &[u8]) -> KResult<Vec<u8>> {
+    let mut res = Vec::with_capacity(bytes.len() / 4 * 3);
+    let mut offset = 0;
+    for line in bytes.split(|&c| c == b'\n') {
+        let line_start = offset;
+        offset += line.len() + 1;
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.is_empty() || (res.is_empty() && line.starts_with(b"begin ")) {
+            continue;
+        }
+        let len = usize::from(line[0].wrapping_sub(b' ') & 0x3f);
+        if len == 0 {
+            break;
+        }
+        let chars = &line[1..];
+        if chars.len() < len.div_ceil(3) * 4 {
+            return Err(text_decoding_error("truncated uuencoded line", line_start));
+        }
+        let mut decoded = Vec::with_capacity(len + 2);
+        for (g, group) in chars.chunks(4).take(len.div_ceil(3)).enumerate() {
+            let mut acc = 0u32;
+            for (j, &c) in group.iter().enumerate() {
+                if !(b' '..=b'`').contains(&c) {
+                    return Err(text_decoding_error(
+                        "invalid uuencode character",
+                        line_start + 1 + g * 4 + j,
+                    ));
+                }
+                acc = (acc << 6) | u32::from((c - b' ') & 0x3f);
+            }
+            decoded.extend_from_slice(&acc.to_be_bytes()[1..]);
+        }
+        res.extend_from_slice(&decoded[..len]);
+    }
+    Ok(res)
+}
+
+/// Inverse of `process_xor_one` (XOR is its own inverse).
+pub fn unprocess_xor_one(bytes: &[u8], key: u8) -> Vec<u8> {
+    process_xor_one(bytes, key)
+}
+
+/// Inverse of `process_xor_many` (XOR is its own inverse).
+pub fn unprocess_xor_many(bytes: &[u8], key: &[u8]) -> Vec<u8> {
+    process_xor_many(bytes, key)
+}
+
+/// Inverse of `process_rotate_left`.
+pub fn unprocess_rotate_left(bytes: &[u8], amount: u8) -> Vec<u8> {
+    let mut res = bytes.to_vec();
+    for i in &mut res {
+        *i = i.rotate_right(amount.into());
+    }
+    res
+}
+
+/// Inverse of `process_rotate_right`.
+pub fn unprocess_rotate_right(bytes: &[u8], amount: u8) -> Vec<u8> {
+    process_rotate_left(bytes, amount)
+}
+
+/// Inverse of `process_rotate_left_group`.
+pub fn unprocess_rotate_left_group(
+    bytes: &[u8],
+    amount: u32,
+    group_size: usize,
+    big_endian: bool,
+) -> Result<Vec<u8>, String> {
+    process_rotate_right_group(bytes, amount, group_size, big_endian)
+}
+
+/// Inverse of `process_rotate_right_group`.
+pub fn unprocess_rotate_right_group(
+    bytes: &[u8],
+    amount: u32,
+    group_size: usize,
+    big_endian: bool,
+) -> Result<Vec<u8>, String> {
+    process_rotate_left_group(bytes, amount, group_size, big_endian)
+}
+
+/// Inverse of `process_zlib`; `level` ranges from 0 (store) to 9 (best).
+pub fn unprocess_zlib(bytes: &[u8], level: u32) -> Result<Vec<u8>, String> {
+    use std::io::Write;
+    let mut enc = ZlibEncoder::new(Vec::new(), Compression::new(level));
+    enc.write_all(bytes).map_err(|e| e.to_string())?;
+    enc.finish().map_err(|e| e.to_string())
+}
+
+/// `process_xor_one` as a `CustomProcessor`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProcessXorOne {
+    pub key: u8,
+}
+
+impl CustomDecoder for ProcessXorOne {
+    fn decode(&self, bytes: &[u8]) -> Result<Vec<u8>, String> {
+        Ok(process_xor_one(bytes, self.key))
+    }
+}
+
+impl CustomProcessor for ProcessXorOne {
+    fn encode(&self, bytes: &[u8]) -> Result<Vec<u8>, String> {
+        Ok(unprocess_xor_one(bytes, self.key))
+    }
+}
+
+/// `process_xor_many` as a `CustomProcessor`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProcessXorMany {
+    pub key: Vec<u8>,
+}
+
+impl ProcessXorMany {
+    // `process_xor_many` indexes into the key, so an empty one would panic
+    fn check_key(&self) -> Result<(), String> {
+        if self.key.is_empty() {
+            return Err("empty XOR key".into());
+        }
+        Ok(())
+    }
+}
+
+impl CustomDecoder for ProcessXorMany {
+    fn decode(&self, bytes: &[u8]) -> Result<Vec<u8>, String> {
+        self.check_key()?;
+        Ok(process_xor_many(bytes, &self.key))
+    }
+}
+
+impl CustomProcessor for ProcessXorMany {
+    fn encode(&self, bytes: &[u8]) -> Result<Vec<u8>, String> {
+        self.check_key()?;
+        Ok(unprocess_xor_many(bytes, &self.key))
+    }
+}
+
+/// `process_rotate_left` as a `CustomProcessor`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProcessRotateLeft {
+    pub amount: u8,
+}
+
+impl CustomDecoder for ProcessRotateLeft {
+    fn decode(&self, bytes: &[u8]) -> Result<Vec<u8>, String> {
+        Ok(process_rotate_left(bytes, self.amount))
+    }
+}
+
+impl CustomProcessor for ProcessRotateLeft {
+    fn encode(&self, bytes: &[u8]) -> Result<Vec<u8>, String> {
+        Ok(unprocess_rotate_left(bytes, self.amount))
+    }
+}
+
+/// `process_rotate_right` as a `CustomProcessor`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProcessRotateRight {
+    pub amount: u8,
+}
+
+impl CustomDecoder for ProcessRotateRight {
+    fn decode(&self, bytes: &[u8]) -> Result<Vec<u8>, String> {
+        Ok(process_rotate_right(bytes, self.amount))
+    }
+}
+
+impl CustomProcessor for ProcessRotateRight {
+    fn encode(&self, bytes: &[u8]) -> Result<Vec<u8>, String> {
+        Ok(unprocess_rotate_right(bytes, self.amount))
+    }
+}
+
+/// `process_rotate_left_group` as a `CustomProcessor`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProcessRotateLeftGroup {
+    pub amount: u32,
+    pub group_size: usize,
+    pub big_endian: bool,
+}
+
+impl CustomDecoder for ProcessRotateLeftGroup {
+    fn decode(&self, bytes: &[u8]) -> Result<Vec<u8>, String> {
+        process_rotate_left_group(bytes, self.amount, self.group_size, self.big_endian)
+    }
+}
+
+impl CustomProcessor for ProcessRotateLeftGroup {
+    fn encode(&self, bytes: &[u8]) -> Result<Vec<u8>, String> {
+        unprocess_rotate_left_group(bytes, self.amount, self.group_size, self.big_endian)
+    }
+}
+
+/// `process_rotate_right_group` as a `CustomProcessor`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProcessRotateRightGroup {
+    pub amount: u32,
+    pub group_size: usize,
+    pub big_endian: bool,
+}
+
+impl CustomDecoder for ProcessRotateRightGroup {
+    fn decode(&self, bytes: &[u8]) -> Result<Vec<u8>, String> {
+        process_rotate_right_group(bytes, self.amount, self.group_size, self.big_endian)
+    }
+}
+
+impl CustomProcessor for ProcessRotateRightGroup {
+    fn encode(&self, bytes: &[u8]) -> Result<Vec<u8>, String> {
+        unprocess_rotate_right_group(bytes, self.amount, self.group_size, self.big_endian)
+    }
+}
+
+/// `process_zlib` as a `CustomProcessor`, compressing at `level` when
+/// encoding.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProcessZlib {
+    pub level: u32,
+}
+
+impl Default for ProcessZlib {
+    fn default() -> Self {
+        ProcessZlib { level: 6 }
+    }
+}
+
+impl CustomDecoder for ProcessZlib {
+    fn decode(&self, bytes: &[u8]) -> Result<Vec<u8>, String> {
+        process_zlib(bytes)
+    }
+}
+
+impl CustomProcessor for ProcessZlib {
+    fn encode(&self, bytes: &[u8]) -> Result<Vec<u8>, String> {
+        unprocess_zlib(bytes, self.level)
+    }
+}
+
+/// `process_base64` as a `CustomDecoder`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProcessBase64 {
+    pub url_safe: bool,
+    pub padded: bool,
+}
+
+impl CustomDecoder for ProcessBase64 {
+    fn decode(&self, bytes: &[u8]) -> Result<Vec<u8>, String> {
+        process_base64(bytes, self.url_safe, self.padded).map_err(text_error_msg)
+    }
+}
+
+/// `process_hex` as a `CustomDecoder`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProcessHex;
+
+impl CustomDecoder for ProcessHex {
+    fn decode(&self, bytes: &[u8]) -> Result<Vec<u8>, String> {
+        process_hex(bytes).map_err(text_error_msg)
+    }
+}
+
+/// `process_ascii85` as a `CustomDecoder`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProcessAscii85;
+
+impl CustomDecoder for ProcessAscii85 {
+    fn decode(&self, bytes: &[u8]) -> Result<Vec<u8>, String> {
+        process_ascii85(bytes).map_err(text_error_msg)
+    }
+}
+
+/// `process_uuencode` as a `CustomDecoder`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProcessUuencode;
+
+impl CustomDecoder for ProcessUuencode {
+    fn decode(&self, bytes: &[u8]) -> Result<Vec<u8>, String> {
+        process_uuencode(bytes).map_err(text_error_msg)
+    }
+}
+
+fn text_error_msg(e: KError) -> String {
+    match e {
+        KError::BytesDecodingError { msg, offset } => format!("{} at offset {}", msg, offset),
+        e => format!("{:?}", e),
+    }
+}
+
+/// A sequence of `process:` steps applied one after another, e.g. XOR
+/// followed by zlib followed by a custom decoder.
+#[derive(Default)]
+pub struct ProcessPipeline {
+    steps: Vec<(String, Box<dyn CustomDecoder>)>,
+}
+
+impl ProcessPipeline {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append a step, named after its type in errors.
+    pub fn then<D: CustomDecoder + 'static>(self, step: D) -> Self {
+        let name = type_name::<D>().rsplit("::").next().unwrap_or_default();
+        self.then_named(name, step)
+    }
+
+    pub fn then_named<D: CustomDecoder + 'static>(mut self, name: &str, step: D) -> Self {
+        self.steps.push((name.to_string(), Box::new(step)));
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.steps.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    /// Run all steps in order. Fails with `KError::ProcessFailed` naming
+    /// the first step that failed.
+    pub fn decode(&self, bytes: &[u8]) -> KResult<Vec<u8>> {
+        let mut data = Cow::Borrowed(bytes);
+        for (step, (name, decoder)) in self.steps.iter().enumerate() {
+            data = Cow::Owned(decoder.decode(&data).map_err(|msg| KError::ProcessFailed {
+                step,
+                name: name.clone(),
+                msg,
+            })?);
+        }
+        Ok(data.into_owned())
+    }
+}
+
+impl CustomDecoder for ProcessPipeline {
+    fn decode(&self, bytes: &[u8]) -> Result<Vec<u8>, String> {
+        ProcessPipeline::decode(self, bytes).map_err(|e| match e {
+            KError::ProcessFailed { step, name, msg } => {
+                format!("step {} ({}): {}", step, name, msg)
+            }
+            e => format!("{:?}", e),
+        })
+    }
+}
+
+impl fmt::Debug for ProcessPipeline {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(self.steps.iter().map(|(name, _)| name))
+            .finish()
+    }
+}
+
+pub fn reverse_string<S: AsRef<str>>(s: S) -> KResult<String> {
+    Ok(s.as_ref().graphemes(true).rev().collect())
+}
+
+pub fn modulo(a: i64, b: i64) -> i64 {
+    a.rem_euclid(b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tempfile::tempdir;
+
+    #[test]
+    fn basic_strip_right() {
+        let b = vec![1, 2, 3, 4, 5, 5, 5, 5];
+        let c = bytes_strip_right(&b, 5);
+
+        assert_eq!([1, 2, 3, 4], c[..]);
+    }
+
+    #[test]
+    fn basic_read_bytes() {
+        let b = vec![1, 2, 3, 4, 5, 6, 7, 8];
+        let reader = BytesReader::from(b);
+
+        assert_eq!(reader.read_bytes(4).unwrap()[..], [1, 2, 3, 4]);
+        assert_eq!(reader.read_bytes(3).unwrap()[..], [5, 6, 7]);
+        assert_eq!(
+            reader.read_bytes(4).unwrap_err(),
+            KError::Eof {
+                requested: 4,
+                available: 1
+            }
+        );
+        assert_eq!(reader.read_bytes(1).unwrap()[..], [8]);
+    }
+
+    #[test]
+    fn read_bits_single() {
+        let b = vec![0x80];
+        let reader = BytesReader::from(b);
+
+        assert_eq!(reader.read_bits_int_be(1).unwrap(), 1);
+    }
+
+    #[test]
+    fn read_bits_multiple() {
+        // 0xA0
+        let b = vec![0b10100000];
+        let reader = BytesReader::from(b);
+
+        assert_eq!(reader.read_bits_int_be(1).unwrap(), 1);
+        assert_eq!(reader.read_bits_int_be(1).unwrap(), 0);
+        assert_eq!(reader.read_bits_int_be(1).unwrap(), 1);
+    }
+
+    #[test]
+    fn read_bits_large() {
+        let b = vec![0b10100000];
+        let reader = BytesReader::from(b);
+
+        assert_eq!(reader.read_bits_int_be(3).unwrap(), 5);
+    }
+
+    #[test]
+    fn read_bits_span() {
+        let b = vec![0x01, 0x80];
+        let reader = BytesReader::from(b);
+
+        assert_eq!(reader.read_bits_int_be(9).unwrap(), 3);
+    }
+
+    #[test]
+    fn read_bits_too_large() {
+        let b: Vec<u8> = vec![1, 2, 3, 4, 5, 6, 7, 8, 9];
+        let reader = BytesReader::from(b);
 
         assert_eq!(
             reader.read_bits_int_be(65).unwrap_err(),
@@ -775,44 +3187,335 @@ mod tests {
     }
 
     #[test]
-    fn read_bytes_term() {
-        let b = vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10];
-        let reader = BytesReader::from(b);
+    fn read_bytes_term() {
+        let b = vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10];
+        let reader = BytesReader::from(b);
+
+        assert_eq!(
+            reader.read_bytes_term(3, false, false, false).unwrap()[..],
+            [1, 2]
+        );
+        assert_eq!(
+            reader.read_bytes_term(3, true, false, true).unwrap()[..],
+            [3]
+        );
+        assert_eq!(
+            reader.read_bytes_term(3, false, true, true).unwrap()[..],
+            []
+        );
+        assert_eq!(
+            reader.read_bytes_term(5, true, true, true).unwrap()[..],
+            [4, 5]
+        );
+        assert_eq!(
+            reader.read_bytes_term(8, false, false, true).unwrap()[..],
+            [6, 7]
+        );
+        assert_eq!(
+            reader.read_bytes_term(11, false, true, true).unwrap_err(),
+            KError::NoTerminatorFound
+        );
+        // restore position
+        reader.seek(7).unwrap();
+        assert_eq!(
+            reader.read_bytes_term(9, true, true, false).unwrap()[..],
+            [8, 9]
+        );
+        assert_eq!(
+            reader.read_bytes_term(10, true, false, false).unwrap()[..],
+            [10]
+        );
+    }
+
+    #[test]
+    fn peek_keeps_state() {
+        let b = vec![0b10100000, 0x01, 0x02, 0x03, 0x04];
+        let reader = BytesReader::from(b);
+
+        assert_eq!(reader.peek_u1().unwrap(), 0b10100000);
+        assert_eq!(reader.peek_u4le().unwrap(), 0x030201a0);
+        assert_eq!(reader.pos(), 0);
+        assert_eq!(reader.read_bits_int_be(3).unwrap(), 5);
+        assert_eq!(reader.peek_bits_int_be(2).unwrap(), 0);
+        assert_eq!(reader.peek_bytes(2).unwrap()[..], [0x01, 0x02]);
+        assert_eq!(
+            reader.peek_bytes(5).unwrap_err(),
+            KError::Eof {
+                requested: 5,
+                available: 4
+            }
+        );
+        // pending bits survive the peeks above
+        assert_eq!(reader.read_bits_int_be(5).unwrap(), 0);
+        assert_eq!(reader.read_u2be().unwrap(), 0x0102);
+    }
+
+    #[test]
+    fn read_arrays() {
+        let b = vec![1, 0, 2, 0, 0, 0, 0x80, 0x3f, 0xff];
+        let reader = BytesReader::from(b);
+
+        assert_eq!(reader.read_u2le_array(2).unwrap(), [1, 2]);
+        let mut dst = [0f32; 1];
+        reader.read_f4le_into(&mut dst).unwrap();
+        assert_eq!(dst, [1.0]);
+        assert_eq!(
+            reader.read_s2be_array(1).unwrap_err(),
+            KError::Eof {
+                requested: 2,
+                available: 1
+            }
+        );
+        assert_eq!(reader.read_s1_array(1).unwrap(), [-1]);
+        assert!(reader.read_u8be_array(0).unwrap().is_empty());
+
+        // spans several internal chunks
+        let values: Vec<u32> = (0..3000).map(|i| i * 0x10001).collect();
+        let bytes: Vec<u8> = values.iter().flat_map(|v| v.to_be_bytes()).collect();
+        let reader = BytesReader::from(bytes);
+        let mut dst = vec![0u32; 2999];
+        reader.read_u4be_into(&mut dst).unwrap();
+        assert_eq!(dst[..], values[..2999]);
+        assert!(reader.read_u4be_array(2).is_err());
+        assert_eq!(reader.pos(), 2999 * 4);
+    }
+
+    #[test]
+    fn read_bytes_into_reuses_buffer() {
+        let b = vec![1, 2, 3, 4, 5, 6, 7, 8];
+        let reader = BytesReader::from(b);
+
+        let mut buf = [0u8; 3];
+        reader.read_bytes_into(&mut buf).unwrap();
+        assert_eq!(buf, [1, 2, 3]);
+        reader.read_bytes_into(&mut buf).unwrap();
+        assert_eq!(buf, [4, 5, 6]);
+        assert_eq!(
+            reader.read_bytes_into(&mut buf).unwrap_err(),
+            KError::Eof {
+                requested: 3,
+                available: 2
+            }
+        );
+
+        let mut rest = vec![0xff; 16];
+        assert_eq!(reader.read_bytes_full_into(&mut rest).unwrap(), 2);
+        assert_eq!(rest, [7, 8]);
+        assert!(reader.is_eof());
+    }
+
+    #[test]
+    fn bytes_to_str_policies() {
+        let b = b"ok\xffok\xe2\x82";
+        assert_eq!(bytes_to_str(b, "utf-8").unwrap(), "ok\u{fffd}ok\u{fffd}");
+        assert_eq!(
+            bytes_to_str_with(b, "utf-8", DecodingPolicy::Ignore).unwrap(),
+            "okok"
+        );
+        assert_eq!(
+            bytes_to_str_with(b, "utf-8", DecodingPolicy::Escape).unwrap(),
+            "ok\\xffok\\xe2\\x82"
+        );
+        assert!(matches!(
+            bytes_to_str_with(b, "utf-8", DecodingPolicy::Strict).unwrap_err(),
+            KError::BytesDecodingError { offset: 2, .. }
+        ));
+        assert!(matches!(
+            bytes_to_str_with(&b[3..], "utf-8", DecodingPolicy::Strict).unwrap_err(),
+            KError::BytesDecodingError { offset: 2, .. }
+        ));
+        assert_eq!(
+            bytes_to_str_with(b"abc", "ascii", DecodingPolicy::Strict).unwrap(),
+            "abc"
+        );
+    }
 
+    #[test]
+    fn str_to_bytes_labels() {
+        assert_eq!(str_to_bytes("héllo", "latin1").unwrap(), b"h\xe9llo");
+        assert_eq!(str_to_bytes("hé", "utf-16le").unwrap(), b"h\0\xe9\0");
+        assert_eq!(str_to_bytes("a€b", "koi8-r").unwrap(), b"a?b");
         assert_eq!(
-            reader.read_bytes_term(3, false, false, false).unwrap()[..],
-            [1, 2]
+            str_to_bytes_with("a€b", "koi8-r", EncodingPolicy::Ignore).unwrap(),
+            b"ab"
         );
+        assert!(matches!(
+            str_to_bytes_with("aé€", "koi8-r", EncodingPolicy::Strict).unwrap_err(),
+            KError::StrEncodingError { offset: 1, .. }
+        ));
+        assert_eq!(str_to_bytes("Ç░ ■", "IBM437").unwrap(), b"\x80\xb0 \xfe");
+        assert!(matches!(
+            str_to_bytes_with("ab€", "cp437", EncodingPolicy::Strict).unwrap_err(),
+            KError::StrEncodingError { offset: 2, .. }
+        ));
         assert_eq!(
-            reader.read_bytes_term(3, true, false, true).unwrap()[..],
-            [3]
+            str_to_bytes("x", "no-such-encoding").unwrap_err(),
+            KError::UnknownEncoding {
+                name: "no-such-encoding".to_string()
+            }
         );
+
+        assert_eq!(bytes_to_str(&[0x00, 0x41, 0xcf], "cp437").unwrap(), "\0A╧");
+        let high: Vec<u8> = (0x80..=0xff).collect();
+        let s = bytes_to_str(&high, "cp437").unwrap();
+        assert_eq!(str_to_bytes_with(&s, "cp437", EncodingPolicy::Strict).unwrap(), high);
+    }
+
+    #[test]
+    fn utf16_utf32_labels() {
+        assert_eq!(bytes_to_str(b"\xff\xfeh\0i\0", "UTF-16").unwrap(), "hi");
+        assert_eq!(bytes_to_str(b"\xfe\xff\0h\0i", "UTF-16").unwrap(), "hi");
+        assert_eq!(bytes_to_str(b"h\0i\0", "UTF-16").unwrap(), "hi");
+        assert_eq!(bytes_to_str(b"\0h\0i", "UTF-16BE").unwrap(), "hi");
         assert_eq!(
-            reader.read_bytes_term(3, false, true, true).unwrap()[..],
-            []
+            bytes_to_str(b"h\0i", "UTF-16LE").unwrap_err(),
+            KError::BytesDecodingError {
+                msg: "length 3 is not a multiple of 2".to_string(),
+                offset: 2
+            }
         );
+
+        let le = b"\xff\xfe\0\0\x00\xf6\x01\0h\0\0\0";
+        assert_eq!(bytes_to_str(le, "UTF-32").unwrap(), "\u{1f600}h");
+        assert_eq!(bytes_to_str(&le[4..], "utf-32le").unwrap(), "\u{1f600}h");
         assert_eq!(
-            reader.read_bytes_term(5, true, true, true).unwrap()[..],
-            [4, 5]
+            bytes_to_str(b"\0\0\xfe\xff\0\0\0h", "utf-32").unwrap(),
+            "h"
         );
+        assert_eq!(bytes_to_str(b"\0\0\0h", "UTF-32BE").unwrap(), "h");
         assert_eq!(
-            reader.read_bytes_term(8, false, false, true).unwrap()[..],
-            [6, 7]
+            bytes_to_str(b"\0\xd8\0\0", "UTF-32LE").unwrap(),
+            "\u{fffd}"
         );
+        assert!(matches!(
+            bytes_to_str_with(b"\xff\xfe\0\0\0\xd8\0\0", "UTF-32", DecodingPolicy::Strict)
+                .unwrap_err(),
+            KError::BytesDecodingError { offset: 4, .. }
+        ));
+
+        assert_eq!(str_to_bytes("h\u{1f600}", "UTF-32BE").unwrap(), b"\0\0\0h\0\x01\xf6\x00");
+        assert_eq!(str_to_bytes("hi", "UTF-16").unwrap(), b"h\0i\0");
+    }
+
+    #[test]
+    fn bytes_to_str_cow_borrows() {
+        let s = bytes_to_str_cow("ünï".as_bytes(), "UTF-8").unwrap();
+        assert!(matches!(s, Cow::Borrowed(_)));
+        assert_eq!(s, "ünï");
+        let s = bytes_to_str_cow(b"plain", "windows-1251").unwrap();
+        assert!(matches!(s, Cow::Borrowed(_)));
+        let s = bytes_to_str_cow(b"plain", "cp437").unwrap();
+        assert!(matches!(s, Cow::Borrowed(_)));
+
+        let s = bytes_to_str_cow(b"\xff", "utf-8").unwrap();
+        assert!(matches!(s, Cow::Owned(_)));
+        assert_eq!(s, "\u{fffd}");
+        let s = bytes_to_str_cow(b"caf\xe9", "latin1").unwrap();
+        assert!(matches!(s, Cow::Owned(_)));
+        assert_eq!(s, "café");
+        let s = bytes_to_str_cow(b"h\0", "utf-16le").unwrap();
+        assert!(matches!(s, Cow::Owned(_)));
+        assert_eq!(s, "h");
+        assert!(bytes_to_str_cow(b"x", "no-such-encoding").is_err());
+    }
+
+    #[test]
+    fn legacy_code_pages() {
+        assert_eq!(bytes_to_str(b"\x9b\xd0", "cp850").unwrap(), "øð");
+        assert_eq!(bytes_to_str(b"\x85\xa5", "IBM852").unwrap(), "ůą");
+        assert_eq!(bytes_to_str(b"\x8f", "cp866").unwrap(), "П");
+        assert_eq!(bytes_to_str(b"\x8a", "cp1250").unwrap(), "Š");
+        assert_eq!(bytes_to_str(b"\xe0", "cp1258").unwrap(), "à");
+        assert_eq!(bytes_to_str(b"\x8a", "MacRoman").unwrap(), "ä");
+
+        let ebcdic = b"\xc8\x85\x93\x93\x96\x40\xba\xf1\xbb";
+        assert_eq!(bytes_to_str(ebcdic, "cp037").unwrap(), "Hello [1]");
+        assert_eq!(bytes_to_str(b"\x4a\x5a", "cp500").unwrap(), "[]");
+        assert_eq!(bytes_to_str(b"\xad\xbd\x5f", "cp1047").unwrap(), "[]^");
+        assert_eq!(str_to_bytes("Hello [1]", "ibm037").unwrap(), ebcdic);
+
+        assert_eq!(bytes_to_str(b"HI\x5c\x73\x0d", "PETSCII").unwrap(), "HI£♥\r");
+        assert_eq!(str_to_bytes("HI£♥", "petscii").unwrap(), b"HI\x5c\x73");
+        assert_eq!(bytes_to_str(b"\x05", "petscii").unwrap(), "\u{fffd}");
+        assert!(matches!(
+            bytes_to_str_with(b"A\x05", "petscii", DecodingPolicy::Strict).unwrap_err(),
+            KError::BytesDecodingError { offset: 1, .. }
+        ));
+        assert_eq!(str_to_bytes("a", "petscii").unwrap(), b"?");
+
+        assert_eq!(bytes_to_str(b"Hi\x00\x9b", "ATASCII").unwrap(), "Hi♥\n");
+        assert_eq!(str_to_bytes("Hi♥\n", "atascii").unwrap(), b"Hi\x00\x9b");
+    }
+
+    #[test]
+    fn registered_encodings() {
+        struct RomTable;
+
+        impl CustomStrDecoder for RomTable {
+            fn decode(&self, bytes: &[u8], policy: DecodingPolicy) -> KResult<String> {
+                let mut res = String::new();
+                for (offset, &b) in bytes.iter().enumerate() {
+                    match (b, policy) {
+                        (0x00..=0x19, _) => res.push(char::from(b'A' + b)),
+                        (0xff, _) => res.push(' '),
+                        (_, DecodingPolicy::Strict) => {
+                            return Err(KError::BytesDecodingError {
+                                msg: "not in table".to_string(),
+                                offset,
+                            })
+                        }
+                        (_, DecodingPolicy::Replace) => res.push('\u{fffd}'),
+                        (_, DecodingPolicy::Ignore) => {}
+                        (_, DecodingPolicy::Escape) => res.push_str(&format!("\\x{:02x}", b)),
+                    }
+                }
+                Ok(res)
+            }
+        }
+
+        let unknown = KError::UnknownEncoding {
+            name: "test-rom-table".to_string(),
+        };
+        assert_eq!(bytes_to_str(&[0], "test-rom-table").unwrap_err(), unknown);
+        register_encoding("test-rom-table", RomTable);
         assert_eq!(
-            reader.read_bytes_term(11, false, true, true).unwrap_err(),
-            KError::NoTerminatorFound
+            bytes_to_str(&[7, 8, 0xff, 0], "Test-ROM-Table").unwrap(),
+            "HI A"
         );
-        // restore position
-        reader.seek(7).unwrap();
+        assert_eq!(bytes_to_str(&[0, 0x20], "test-rom-table").unwrap(), "A\u{fffd}");
+        assert!(matches!(
+            bytes_to_str_with(&[0, 0x20], "test-rom-table", DecodingPolicy::Strict).unwrap_err(),
+            KError::BytesDecodingError { offset: 1, .. }
+        ));
         assert_eq!(
-            reader.read_bytes_term(9, true, true, false).unwrap()[..],
-            [8, 9]
+            bytes_to_str_with(&[0, 0x20], "test-rom-table", DecodingPolicy::Ignore).unwrap(),
+            "A"
         );
+        assert!(unregister_encoding("test-rom-table"));
+        assert!(!unregister_encoding("test-rom-table"));
+        assert_eq!(bytes_to_str(&[0], "test-rom-table").unwrap_err(), unknown);
+    }
+
+    #[test]
+    fn read_str_methods() {
+        let reader = BytesReader::from(b"ab\0cd  \xe9t\xe9\0x\0\0\0rest".to_vec());
+
+        assert_eq!(reader.read_str_fixed(3, "UTF-8", None, Some(0)).unwrap(), "ab");
+        assert_eq!(reader.read_str_fixed(4, "ascii", Some(b' '), None).unwrap(), "cd");
+        assert_eq!(reader.read_strz("latin1").unwrap(), "été");
         assert_eq!(
-            reader.read_bytes_term(10, true, false, false).unwrap()[..],
-            [10]
+            reader.read_str_fixed(4, "utf-8", Some(0), Some(0)).unwrap(),
+            "x"
         );
+        assert_eq!(
+            reader.read_str_term("utf-8", b't', true, false, true).unwrap(),
+            "rest"
+        );
+        assert_eq!(reader.read_str_eos("UTF-8").unwrap(), "t");
+        assert_eq!(reader.read_str_eos("UTF-8").unwrap(), "");
+        reader.seek(reader.size() - 4).unwrap();
+        assert_eq!(reader.read_str_eos("cp037").unwrap(), "ÊÁËÈ");
+        assert_eq!(reader.read_strz("utf-8").unwrap_err(), KError::NoTerminatorFound);
     }
 
     #[test]
@@ -841,6 +3544,168 @@ mod tests {
         assert_eq!(expected, res);
     }
 
+    #[test]
+    fn process_rotate_groups() {
+        assert_eq!(process_rotate_right(&[0x48, 0x65], 3), [0x09, 0xAC]);
+        assert_eq!(unprocess_rotate_right(&[0x09, 0xAC], 3), [0x48, 0x65]);
+
+        let le = [0x78, 0x56, 0x34, 0x12, 0xef, 0xcd, 0xab, 0x89];
+        assert_eq!(
+            process_rotate_left_group(&le, 8, 4, false).unwrap(),
+            [0x12, 0x78, 0x56, 0x34, 0x89, 0xef, 0xcd, 0xab]
+        );
+        assert_eq!(
+            process_rotate_left_group(&le, 8, 4, true).unwrap(),
+            [0x56, 0x34, 0x12, 0x78, 0xcd, 0xab, 0x89, 0xef]
+        );
+        assert_eq!(
+            process_rotate_right_group(&[0x00, 0x01], 1, 2, true).unwrap(),
+            [0x80, 0x00]
+        );
+        assert_eq!(
+            process_rotate_left_group(&le, 4, 8, false).unwrap(),
+            u64::from_le_bytes(le).rotate_left(4).to_le_bytes()
+        );
+        assert_eq!(process_rotate_left_group(&le, 32, 4, false).unwrap(), le);
+        assert_eq!(
+            process_rotate_left_group(&le, 3, 1, false).unwrap(),
+            process_rotate_left(&le, 3)
+        );
+        assert!(process_rotate_left_group(&le, 1, 3, false).is_err());
+        assert!(process_rotate_left_group(&le[..6], 1, 4, false).is_err());
+
+        let p = ProcessRotateRightGroup {
+            amount: 13,
+            group_size: 2,
+            big_endian: false,
+        };
+        assert_eq!(p.encode(&p.decode(&le).unwrap()).unwrap(), le);
+    }
+
+    #[test]
+    fn unprocess_round_trips() {
+        let data = b"The quick brown fox jumps over the lazy dog".to_vec();
+        let processors: Vec<Box<dyn CustomProcessor>> = vec![
+            Box::new(ProcessXorOne { key: 0x5a }),
+            Box::new(ProcessXorMany {
+                key: vec![1, 2, 3],
+            }),
+            Box::new(ProcessRotateLeft { amount: 3 }),
+            Box::new(ProcessZlib::default()),
+            Box::new(ProcessZlib { level: 9 }),
+        ];
+        for p in &processors {
+            let encoded = p.encode(&data).unwrap();
+            assert_ne!(encoded, data);
+            assert_eq!(p.decode(&encoded).unwrap(), data);
+        }
+        let empty = ProcessXorMany { key: vec![] };
+        assert_eq!(empty.encode(&data), Err("empty XOR key".to_string()));
+        assert_eq!(empty.decode(&data), Err("empty XOR key".to_string()));
+
+        assert_eq!(unprocess_rotate_left(&[0x48, 0x65], 3), [0x09, 0xAC]);
+        let stored = unprocess_zlib(&data, 0).unwrap();
+        assert!(stored.len() > data.len());
+        assert_eq!(process_zlib(&stored).unwrap(), data);
+    }
+
+    #[test]
+    fn processed_reader() {
+        let data: Vec<u8> = (0..=255).cycle().take(1000).collect();
+
+        let xored = BytesReader::from(process_xor_many(&data, &[1, 2, 3]));
+        let reader = ProcessedReader::new(xored, StreamProcess::XorMany(vec![1, 2, 3])).unwrap();
+        reader.seek(500).unwrap();
+        assert_eq!(reader.read_bytes(10).unwrap()[..], data[500..510]);
+        reader.seek(4).unwrap();
+        assert_eq!(reader.read_u1().unwrap(), 4);
+
+        let rotated = BytesReader::from(unprocess_rotate_left(&data, 3));
+        let reader = ProcessedReader::new(rotated, StreamProcess::RotateLeft(3)).unwrap();
+        assert_eq!(reader.read_bytes_full().unwrap(), data);
+
+        // zlib section in the middle of a parent stream
+        let mut parent = vec![0xee; 5];
+        let compressed = unprocess_zlib(&data, 6).unwrap();
+        let compressed_len = compressed.len();
+        parent.extend(compressed);
+        parent.extend([0xee; 5]);
+        let section = ExtentReader::new(
+            BytesReader::from(parent),
+            vec![Extent {
+                logical: 0,
+                physical: 5,
+                len: compressed_len,
+            }],
+            compressed_len,
+        )
+        .unwrap();
+        let reader = ProcessedReader::new(KStream::clone(&section), StreamProcess::Zlib).unwrap();
+        assert_eq!(reader.size(), 1000);
+        reader.seek(900).unwrap();
+        assert_eq!(reader.read_bytes(50).unwrap()[..], data[900..950]);
+        reader.seek(10).unwrap();
+        assert_eq!(reader.read_u4be().unwrap(), 0x0a0b0c0d);
+        assert_eq!(reader.read_bytes(16).unwrap()[..], data[14..30]);
+        assert_eq!(
+            reader.read_bytes(1000).unwrap_err(),
+            KError::Eof {
+                requested: 1000,
+                available: 970
+            }
+        );
+        let cloned = KStream::clone(&reader);
+        assert_eq!(cloned.read_bytes_full().unwrap()[..], data[30..]);
+
+        assert!(
+            ProcessedReader::new(BytesReader::from(vec![1, 2, 3]), StreamProcess::Zlib).is_err()
+        );
+        assert!(matches!(
+            ProcessedReader::new(BytesReader::from(vec![1, 2, 3]), StreamProcess::XorMany(vec![])),
+            Err(KError::InvalidArgument { .. })
+        ));
+    }
+
+    #[test]
+    fn processed_reader_after_error() {
+        // fails one read once armed
+        struct Flaky(std::io::Cursor<Vec<u8>>, Rc<std::cell::Cell<bool>>);
+        impl Read for Flaky {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                if self.1.replace(false) {
+                    return Err(std::io::Error::other("flaky"));
+                }
+                self.0.read(buf)
+            }
+        }
+        impl Seek for Flaky {
+            fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+                self.0.seek(pos)
+            }
+        }
+
+        // incompressible, so that the decoder keeps reading the parent
+        let mut x: u32 = 1;
+        let data: Vec<u8> = (0..300_000)
+            .map(|_| {
+                x = x.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                (x >> 16) as u8
+            })
+            .collect();
+        let armed = Rc::new(std::cell::Cell::new(false));
+        let compressed = std::io::Cursor::new(unprocess_zlib(&data, 6).unwrap());
+        let parent = BytesReader::from_reader(Flaky(compressed, armed.clone())).unwrap();
+        let reader = ProcessedReader::new(parent, StreamProcess::Zlib).unwrap();
+
+        reader.seek(100_000).unwrap();
+        assert_eq!(reader.read_bytes(10).unwrap()[..], data[100_000..100_010]);
+        armed.set(true);
+        reader.seek(200_000).unwrap();
+        assert!(reader.read_bytes(10).is_err());
+        reader.seek(250_000).unwrap();
+        assert_eq!(reader.read_bytes(10).unwrap()[..], data[250_000..250_010]);
+    }
+
     #[test]
     fn basic_seek() {
         let b = vec![1, 2, 3, 4, 5, 6, 7, 8];
@@ -855,6 +3720,171 @@ mod tests {
         reader.seek(9).unwrap();
     }
 
+    #[test]
+    fn std_io_traits() {
+        use std::io::BufRead;
+
+        let reader = BytesReader::from(b"head\nline two\ntail".to_vec());
+        let mut sub = BytesReader::from(reader.read_bytes(14).unwrap());
+
+        let mut line = String::new();
+        sub.read_line(&mut line).unwrap();
+        assert_eq!(line, "head\n");
+        assert_eq!(sub.pos(), 5);
+
+        Seek::seek(&mut sub, SeekFrom::End(-4)).unwrap();
+        let mut rest = vec![];
+        sub.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, b"two\n");
+
+        assert_eq!(Seek::seek(&mut sub, SeekFrom::Current(-9)).unwrap(), 5);
+        let mut buf = [0; 4];
+        sub.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"line");
+        assert!(Seek::seek(&mut sub, SeekFrom::Current(-10)).is_err());
+        assert_eq!(sub.read(&mut [0; 32]).unwrap(), 5);
+        assert_eq!(sub.read(&mut [0; 32]).unwrap(), 0);
+
+        sub.seek(0).unwrap();
+        assert_eq!(sub.fill_buf().unwrap(), b"head\nline two\n");
+        let cloned = Clone::clone(&sub);
+        assert!(!format!("{:?}", cloned).contains("104, 101, 97, 100"));
+    }
+
+    #[test]
+    fn fill_buf_after_error() {
+        use std::io::BufRead;
+
+        // fails the first read only
+        struct Flaky(std::io::Cursor<Vec<u8>>, bool);
+        impl Read for Flaky {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                if std::mem::replace(&mut self.1, false) {
+                    return Err(std::io::Error::other("flaky"));
+                }
+                self.0.read(buf)
+            }
+        }
+        impl Seek for Flaky {
+            fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+                self.0.seek(pos)
+            }
+        }
+
+        let mut reader =
+            BytesReader::from_reader(Flaky(std::io::Cursor::new(vec![1, 2, 3]), true)).unwrap();
+        let err = reader.fill_buf().unwrap_err();
+        assert_eq!(err.to_string(), "flaky");
+        assert_eq!(
+            KError::from(err),
+            KError::IoError {
+                msg: "flaky".to_string()
+            }
+        );
+        assert_eq!(reader.fill_buf().unwrap(), [1, 2, 3]);
+    }
+
+    #[test]
+    fn chained_reader() {
+        let reader = ChainedReader::new(vec![
+            BytesReader::from(vec![1, 2, 3]),
+            BytesReader::from(vec![]),
+            BytesReader::from(vec![4]),
+            BytesReader::from(vec![5, 6]),
+        ]);
+
+        assert_eq!(reader.size(), 6);
+        assert_eq!(reader.read_u2be().unwrap(), 0x0102);
+        assert_eq!(reader.read_u4be().unwrap(), 0x03040506);
+        assert!(reader.is_eof());
+        reader.seek(3).unwrap();
+        assert_eq!(reader.read_bytes(1).unwrap()[..], [4]);
+        assert_eq!(
+            reader.read_bytes(3).unwrap_err(),
+            KError::Eof {
+                requested: 3,
+                available: 2
+            }
+        );
+        let cloned = KStream::clone(&reader);
+        assert_eq!(cloned.pos(), 4);
+        assert_eq!(cloned.size(), 6);
+        assert_eq!(cloned.read_bytes_full().unwrap()[..], [5, 6]);
+        cloned.seek(1).unwrap();
+        assert_eq!(cloned.read_bytes(4).unwrap()[..], [2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn extent_reader() {
+        let parent = BytesReader::from(vec![0xaa, 1, 2, 3, 0xbb, 4, 5]);
+        let reader = ExtentReader::new(
+            parent,
+            vec![
+                Extent {
+                    logical: 4,
+                    physical: 5,
+                    len: 2,
+                },
+                Extent {
+                    logical: 0,
+                    physical: 1,
+                    len: 3,
+                },
+            ],
+            8,
+        )
+        .unwrap();
+
+        assert_eq!(reader.size(), 8);
+        assert_eq!(reader.read_bytes(8).unwrap()[..], [1, 2, 3, 0, 4, 5, 0, 0]);
+        reader.seek(2).unwrap();
+        assert_eq!(reader.read_u4be().unwrap(), 0x03000405);
+        assert_eq!(
+            reader.read_bytes(3).unwrap_err(),
+            KError::Eof {
+                requested: 3,
+                available: 2
+            }
+        );
+        let cloned = KStream::clone(&reader);
+        cloned.seek(3).unwrap();
+        assert_eq!(cloned.read_bytes_full().unwrap()[..], [0, 4, 5, 0, 0]);
+
+        let overlapping = vec![
+            Extent {
+                logical: 0,
+                physical: 0,
+                len: 8,
+            },
+            Extent {
+                logical: 2,
+                physical: 0,
+                len: 2,
+            },
+        ];
+        assert!(matches!(
+            ExtentReader::new(BytesReader::from(vec![0; 8]), overlapping, 8),
+            Err(KError::InvalidArgument { .. })
+        ));
+    }
+
+    #[test]
+    fn open_multi_files() {
+        let tmp_dir = tempdir().unwrap();
+        let mut paths = vec![];
+        for (i, part) in [&[1u8, 2][..], &[3, 4, 5]].iter().enumerate() {
+            let path = tmp_dir.path().join(format!("image.{:03}", i + 1));
+            std::fs::write(&path, part).unwrap();
+            paths.push(path);
+        }
+        let reader = BytesReader::open_multi(&paths).unwrap();
+
+        assert_eq!(reader.size(), 5);
+        reader.seek(1).unwrap();
+        assert_eq!(reader.read_u2le().unwrap(), 0x0302);
+        assert_eq!(reader.read_bytes_full().unwrap()[..], [4, 5]);
+    }
+
     fn dump_and_open(bytes: &[u8]) -> BytesReader {
         let tmp_dir = tempdir().unwrap();
         let file_path = tmp_dir.path().join("test.txt");
@@ -893,4 +3923,408 @@ mod tests {
         assert_eq!(reader.read_bytes(4).unwrap()[..], [5, 6, 7, 8]);
         reader.seek(9).unwrap();
     }
+
+    #[test]
+    fn text_processes() {
+        assert_eq!(process_base64(b"aGVsbG8=", false, true).unwrap(), b"hello");
+        assert_eq!(process_base64(b"aGVsbG8", false, false).unwrap(), b"hello");
+        assert_eq!(
+            process_base64(b"aGVs\r\nbG8h", false, true).unwrap(),
+            b"hello!"
+        );
+        assert_eq!(process_base64(b"-_8=", true, true).unwrap(), [0xfb, 0xff]);
+        assert_eq!(
+            process_base64(b"+/8", false, true),
+            Err(KError::BytesDecodingError {
+                msg: "missing base64 padding".to_string(),
+                offset: 3
+            })
+        );
+        assert_eq!(
+            process_base64(b"aGVsbG8=", false, false),
+            Err(KError::BytesDecodingError {
+                msg: "invalid base64 character".to_string(),
+                offset: 7
+            })
+        );
+        assert!(process_base64(b"-_8=", false, true).is_err());
+        assert!(process_base64(b"aGVsb", false, false).is_err());
+        assert!(process_base64(b"aG==bG8=", false, true).is_err());
+
+        assert_eq!(
+            process_hex(b"DEad be\nef").unwrap(),
+            [0xde, 0xad, 0xbe, 0xef]
+        );
+        assert_eq!(
+            process_hex(b"abc"),
+            Err(KError::BytesDecodingError {
+                msg: "odd number of hex digits".to_string(),
+                offset: 2
+            })
+        );
+        assert!(process_hex(b"0g").is_err());
+
+        assert_eq!(process_ascii85(b"<~87cURDZ~>").unwrap(), b"Hello");
+        assert_eq!(process_ascii85(b"87cUR\nD]p8").unwrap(), b"Hello!!");
+        assert_eq!(process_ascii85(b"z!!").unwrap(), [0, 0, 0, 0, 0]);
+        assert!(process_ascii85(b"s8W-\"").is_err());
+        assert!(process_ascii85(b"87cURD{").is_err());
+
+        let uu = b"begin 644 cat.txt\n#0V%T\n`\nend\n";
+        assert_eq!(process_uuencode(uu).unwrap(), b"Cat");
+        assert_eq!(process_uuencode(b"%:&5L;&\\`\n`\n").unwrap(), b"hello");
+        assert!(process_uuencode(b"&:&5L\n").is_err());
+    }
+
+    #[test]
+    fn process_pipeline() {
+        let plain = b"layered payload".to_vec();
+        let zipped = unprocess_zlib(&plain, 9).unwrap();
+        let rotated = unprocess_rotate_left(&zipped, 3);
+        let hex: String = rotated.iter().map(|b| format!("{:02x}", b)).collect();
+        let packed = process_xor_many(hex.as_bytes(), &[0x10, 0x20]);
+
+        let pipeline = ProcessPipeline::new()
+            .then(ProcessXorMany {
+                key: vec![0x10, 0x20],
+            })
+            .then(ProcessHex)
+            .then(ProcessRotateLeft { amount: 3 })
+            .then(ProcessZlib::default());
+        assert_eq!(pipeline.len(), 4);
+        assert_eq!(pipeline.decode(&packed).unwrap(), plain);
+        assert_eq!(ProcessPipeline::new().decode(&plain).unwrap(), plain);
+
+        match pipeline.decode(&packed[..packed.len() - 1]) {
+            Err(KError::ProcessFailed { step, name, msg }) => {
+                assert_eq!(step, 1);
+                assert_eq!(name, "ProcessHex");
+                assert_eq!(
+                    msg,
+                    format!("odd number of hex digits at offset {}", packed.len() - 2)
+                );
+            }
+            r => panic!("unexpected {:?}", r),
+        }
+        let pipeline = ProcessPipeline::new()
+            .then(ProcessBase64 {
+                url_safe: false,
+                padded: true,
+            })
+            .then_named("inflate", ProcessZlib::default());
+        match pipeline.decode(b"AAAA") {
+            Err(KError::ProcessFailed { step: 1, name, .. }) => assert_eq!(name, "inflate"),
+            r => panic!("unexpected {:?}", r),
+        }
+        let pipeline = ProcessPipeline::new().then(ProcessXorMany { key: vec![] });
+        match pipeline.decode(&plain) {
+            Err(KError::ProcessFailed { step: 0, msg, .. }) => assert_eq!(msg, "empty XOR key"),
+            r => panic!("unexpected {:?}", r),
+        }
+    }
+
+    #[derive(Default, Debug)]
+    struct Record {
+        magic: RefCell<u8>,
+        len: RefCell<Option<u16>>,
+        body: RefCell<Vec<u8>>,
+        tail: RefCell<u8>,
+    }
+
+    impl KStruct for Record {
+        type Root = Record;
+        type Parent = KStructUnit;
+
+        fn read<S: KStream>(
+            self_rc: &OptRc<Self>,
+            _io: &S,
+            _root: SharedType<Self::Root>,
+            _parent: SharedType<Self::Parent>,
+        ) -> KResult<()> {
+            *self_rc.magic.borrow_mut() = _io.read_u1()?;
+            *self_rc.len.borrow_mut() = recover(_io.read_u2be().and_then(|len| {
+                if len > 4 {
+                    Err(KError::ValidationFailed(ValidationFailedError {
+                        kind: ValidationKind::GreaterThan,
+                        src_path: "/seq/1".to_string(),
+                    }))
+                } else {
+                    Ok(len)
+                }
+            }))?;
+            let len = self_rc.len.borrow().unwrap_or(0);
+            *self_rc.body.borrow_mut() = recover(_io.read_bytes(len.into()))?.unwrap_or_default();
+            *self_rc.tail.borrow_mut() = _io.read_u1()?;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn lenient_read() {
+        let reader = BytesReader::from(vec![1, 0, 2, 0xaa, 0xbb, 9]);
+        let r = Record::read_into_lenient::<_, Record>(&reader, None, None);
+        assert!(r.is_complete());
+        assert_eq!(*r.value.body.borrow(), [0xaa, 0xbb]);
+        assert!(!is_lenient());
+
+        let reader = BytesReader::from(vec![1, 0, 9, 7]);
+        let r = Record::read_into_lenient::<_, Record>(&reader, None, None);
+        assert_eq!(r.errors.len(), 1);
+        assert_eq!(*r.value.len.borrow(), None);
+        assert_eq!(*r.value.tail.borrow(), 7);
+
+        let reader = BytesReader::from(vec![1, 0, 3, 0xaa]);
+        let r = Record::read_into_lenient::<_, Record>(&reader, None, None);
+        assert_eq!(*r.value.magic.borrow(), 1);
+        assert_eq!(*r.value.len.borrow(), Some(3));
+        assert!(matches!(r.errors[..], [KError::Eof { .. }]));
+        assert_eq!(*r.value.tail.borrow(), 0xaa);
+
+        let reader = BytesReader::from(vec![1]);
+        let r = Record::read_into_lenient::<_, Record>(&reader, None, None);
+        assert_eq!(*r.value.magic.borrow(), 1);
+        assert!(matches!(
+            r.errors[..],
+            [KError::Eof { .. }, KError::Eof { .. }]
+        ));
+
+        let reader = BytesReader::from(vec![1, 0, 9, 7]);
+        assert!(Record::read_into::<_, Record>(&reader, None, None).is_err());
+    }
+
+    #[derive(Default, Debug)]
+    struct Outer {
+        inner: RefCell<OptRc<Record>>,
+    }
+
+    impl KStruct for Outer {
+        type Root = Outer;
+        type Parent = KStructUnit;
+
+        fn read<S: KStream>(
+            self_rc: &OptRc<Self>,
+            _io: &S,
+            _root: SharedType<Self::Root>,
+            _parent: SharedType<Self::Parent>,
+        ) -> KResult<()> {
+            *self_rc.inner.borrow_mut() = Self::read_into::<_, Record>(_io, None, None)?;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn error_context() {
+        let reader = BytesReader::from(vec![1, 0, 9, 7]);
+        assert!(matches!(
+            Outer::read_into::<_, Outer>(&reader, None, None),
+            Err(KError::ValidationFailed(_))
+        ));
+
+        let reader = BytesReader::from(vec![1, 0, 9, 7]);
+        let err =
+            with_error_context(|| Outer::read_into::<_, Outer>(&reader, None, None)).unwrap_err();
+        assert_eq!(
+            err.root_cause(),
+            &KError::ValidationFailed(ValidationFailedError {
+                kind: ValidationKind::GreaterThan,
+                src_path: "/seq/1".to_string(),
+            })
+        );
+        let outer = type_name::<Outer>();
+        let record = type_name::<Record>();
+        match &err {
+            KError::Context { path, .. } => assert_eq!(path[..], [outer, record]),
+            e => panic!("unexpected {:?}", e),
+        }
+
+        let reader = BytesReader::from(vec![1]);
+        let r = with_error_context(|| Record::read_into_lenient::<_, Record>(&reader, None, None));
+        for err in &r.errors {
+            match err {
+                KError::Context { path, source } => {
+                    assert_eq!(path[..], [record]);
+                    assert!(matches!(**source, KError::Eof { .. }));
+                }
+                e => panic!("unexpected {:?}", e),
+            }
+        }
+        assert_eq!(r.errors.len(), 2);
+
+        let err = KError::Eof {
+            requested: 4,
+            available: 2,
+        }
+        .with_context("width")
+        .with_context("png::Ihdr")
+        .with_context("png::Chunk");
+        assert_eq!(
+            err.to_string(),
+            "png::Chunk > png::Ihdr > width: Eof { requested: 4, available: 2 }"
+        );
+        assert_eq!(
+            std::io::Error::from(err).kind(),
+            std::io::ErrorKind::UnexpectedEof
+        );
+        assert_eq!(KError::CastError.to_string(), "CastError");
+    }
+
+    #[test]
+    fn render_error_hexdump() {
+        let data: Vec<u8> = (0..0x1e).collect();
+        let err = KError::Eof {
+            requested: 4,
+            available: 2,
+        }
+        .with_context("width");
+        let expected = "\
+error: width: Eof { requested: 4, available: 2 }
+ --> offset 0x1c, 4 bytes (2 past the end)
+00000000  00 01 02 03 04 05 06 07 08 09 0a 0b 0c 0d 0e 0f  |................|
+00000010  10 11 12 13 14 15 16 17 18 19 1a 1b 1c 1d        |..............  |
+                                              ^^ ^^ ^^ ^^
+";
+        assert_eq!(render_error(&err, &data, 0x1c), expected);
+
+        let reader = BytesReader::from(data.clone());
+        reader.seek(3).unwrap();
+        assert_eq!(reader.render_error(&err, 0x1c).unwrap(), expected);
+        assert_eq!(reader.pos(), 3);
+
+        let data = b"0123456789abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ!?0123456789abcdefghijklmnopqrstuvwxyz";
+        let err = KError::BytesDecodingError {
+            msg: "bad".to_string(),
+            offset: 2,
+        };
+        let out = render_error(&err, data, 0x40);
+        assert_eq!(out.lines().count(), 2 + 5 + 1);
+        assert!(out.contains("offset 0x42, 1 byte\n00000020"));
+        assert!(out.contains("00000040  30 31 32 33"));
+        assert!(out.contains("\n                ^^\n"));
+        assert!(out.ends_with("|wxyz            |\n"));
+
+        let reader = BytesReader::from(data.to_vec());
+        assert_eq!(reader.render_error(&err, 0x40).unwrap(), out);
+
+        // extent pointing past the end of its parent
+        let broken = ExtentReader::new(
+            BytesReader::from(vec![1]),
+            vec![Extent {
+                logical: 0,
+                physical: 10,
+                len: 4,
+            }],
+            4,
+        )
+        .unwrap();
+        assert!(broken.render_error(&err, 0).is_err());
+
+        let err = KError::ValidationFailed(ValidationFailedError {
+            kind: ValidationKind::NotEqual,
+            src_path: "/seq/0".to_string(),
+        });
+        let out = render_error_span(&err, b"GIF89a", 0, 3);
+        assert!(out.contains("offset 0x0, 3 bytes\n00000000  47 49 46 38 39 61"));
+        assert!(out.contains("\n          ^^ ^^ ^^\n"));
+    }
+
+    #[derive(Default, Debug)]
+    struct LogStorage {
+        logs: Arena<Log>,
+        entries: Arena<Entry>,
+    }
+
+    impl ArenaStorage<Log> for LogStorage {
+        fn arena(&self) -> &Arena<Log> {
+            &self.logs
+        }
+        fn arena_mut(&mut self) -> &mut Arena<Log> {
+            &mut self.logs
+        }
+    }
+
+    impl ArenaStorage<Entry> for LogStorage {
+        fn arena(&self) -> &Arena<Entry> {
+            &self.entries
+        }
+        fn arena_mut(&mut self) -> &mut Arena<Entry> {
+            &mut self.entries
+        }
+    }
+
+    #[derive(Default, Debug)]
+    struct Log {
+        entry_size: u8,
+        entries: Vec<ArenaRef<Entry>>,
+    }
+
+    #[derive(Default, Debug)]
+    struct Entry {
+        parent: Option<ArenaRef<Log>>,
+        data: Vec<u8>,
+    }
+
+    impl KArenaStruct for Log {
+        type Root = Log;
+        type Parent = KStructUnit;
+        type Storage = LogStorage;
+
+        fn read<S: KStream>(
+            storage: &mut LogStorage,
+            self_ref: ArenaRef<Self>,
+            _io: &S,
+            _root: ArenaRef<Self::Root>,
+            _parent: Option<ArenaRef<Self::Parent>>,
+        ) -> KResult<()> {
+            storage.logs[self_ref].entry_size = _io.read_u1()?;
+            while !_io.is_eof() {
+                let e =
+                    Self::read_into_arena::<_, Entry>(storage, _io, Some(_root), Some(self_ref))?;
+                storage.logs[self_ref].entries.push(e);
+            }
+            Ok(())
+        }
+    }
+
+    impl KArenaStruct for Entry {
+        type Root = Log;
+        type Parent = Log;
+        type Storage = LogStorage;
+
+        fn read<S: KStream>(
+            storage: &mut LogStorage,
+            self_ref: ArenaRef<Self>,
+            _io: &S,
+            _root: ArenaRef<Self::Root>,
+            _parent: Option<ArenaRef<Self::Parent>>,
+        ) -> KResult<()> {
+            let parent = _parent.ok_or(KError::MissingParent)?;
+            storage.entries[self_ref].parent = Some(parent);
+            let size = storage.logs[parent].entry_size;
+            storage.entries[self_ref].data = _io.read_bytes(size.into())?;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn arena_read() {
+        let mut storage = LogStorage::default();
+        let reader = BytesReader::from(vec![2, 1, 2, 3, 4, 5, 6]);
+        let log = Log::read_into_arena::<_, Log>(&mut storage, &reader, None, None).unwrap();
+        assert_eq!(storage.logs.len(), 1);
+        assert_eq!(storage.entries.len(), 3);
+        let entries = &storage.logs[log].entries;
+        assert_eq!(storage.entries[entries[1]].data, [3, 4]);
+        assert_eq!(storage.entries[entries[2]].parent, Some(log));
+        assert_eq!(
+            storage.entries.iter().map(|(r, _)| r).collect::<Vec<_>>(),
+            *entries
+        );
+        assert!(log.cast::<Entry>().is_none());
+
+        let reader = BytesReader::from(vec![2, 1, 2, 3]);
+        let err = Log::read_into_arena::<_, Log>(&mut storage, &reader, None, None).unwrap_err();
+        assert!(matches!(err.root_cause(), KError::Eof { .. }));
+        assert_eq!(storage.logs.len(), 2);
+        assert_eq!(storage.entries.len(), 5);
+    }
 }